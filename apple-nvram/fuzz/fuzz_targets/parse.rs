@@ -0,0 +1,19 @@
+// SPDX-License-Identifier: MIT
+#![no_main]
+
+use apple_nvram::nvram_parse;
+use libfuzzer_sys::fuzz_target;
+
+// run with `cargo fuzz run parse` from apple-nvram/
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut nv) = nvram_parse(data) {
+        for part in nv.partitions() {
+            for var in part.variables() {
+                let _ = var.value();
+            }
+        }
+        let _ = nv.serialize();
+        nv.prepare_for_write();
+        let _ = nv.serialize();
+    }
+});
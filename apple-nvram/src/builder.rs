@@ -0,0 +1,186 @@
+use alloc::vec::Vec;
+
+use crate::{v1v2, v3, Error, OwnedVariable, Result, VarType, Version};
+
+// lays out a fresh image from scratch, for initializing blank flash or
+// making test images. the result is parsed back by nvram_parse as is. The
+// defaults are the usual layout of each version, build() fails with
+// InvalidLayout on sizes that don't make an image
+#[derive(Clone, Debug)]
+pub struct NvramBuilder {
+    version: Version,
+    common_size: usize,
+    system_size: usize,
+    banks: Option<usize>,
+    image_size: Option<usize>,
+    variables: Vec<OwnedVariable>,
+}
+
+impl NvramBuilder {
+    pub fn new(version: Version) -> NvramBuilder {
+        let (common_size, system_size) = match version {
+            Version::V1V2 => (0x10000 - 32 - 0x1000, 0x1000),
+            Version::V3 => (0xC000, 0x4000),
+        };
+        NvramBuilder {
+            version,
+            common_size,
+            system_size,
+            banks: None,
+            image_size: None,
+            variables: Vec::new(),
+        }
+    }
+
+    // section sizes are multiples of 16 and, with a v1v2 bank's 32 byte
+    // header, have to fit in a 64K bank
+    pub fn common_size(mut self, size: usize) -> NvramBuilder {
+        self.common_size = size;
+        self
+    }
+
+    pub fn system_size(mut self, size: usize) -> NvramBuilder {
+        self.system_size = size;
+        self
+    }
+
+    // between 1 and 16 on v3, v1v2 images always have two. Defaults to 2
+    pub fn banks(mut self, banks: usize) -> NvramBuilder {
+        self.banks = Some(banks);
+        self
+    }
+
+    // the whole image in 64K banks, instead of banks() or agreeing with it
+    pub fn image_size(mut self, size: usize) -> NvramBuilder {
+        self.image_size = Some(size);
+        self
+    }
+
+    fn bank_count(&self) -> Result<usize> {
+        let bank_size = match self.version {
+            Version::V1V2 => v1v2::BANK_SIZE,
+            Version::V3 => v3::PARTITION_SIZE,
+        };
+        let sized = match self.image_size {
+            Some(size) if !size.is_multiple_of(bank_size) => {
+                return Err(Error::InvalidLayout(
+                    "image size isn't a whole number of banks",
+                ))
+            }
+            Some(size) => Some(size / bank_size),
+            None => None,
+        };
+        let banks = match (self.banks, sized) {
+            (Some(banks), Some(sized)) if banks != sized => {
+                return Err(Error::InvalidLayout(
+                    "image size doesn't match the bank count",
+                ))
+            }
+            (banks, sized) => banks.or(sized).unwrap_or(2),
+        };
+        match (self.version, banks) {
+            (Version::V1V2, 2) => Ok(2),
+            (Version::V1V2, _) => Err(Error::InvalidLayout("v1v2 images have two banks")),
+            (Version::V3, banks @ 1..=16) => Ok(banks),
+            (Version::V3, _) => Err(Error::InvalidLayout("v3 images have 1 to 16 banks")),
+        }
+    }
+
+    pub fn variable(mut self, typ: VarType, name: &[u8], value: &[u8]) -> NvramBuilder {
+        self.variables.push(OwnedVariable {
+            typ,
+            name: name.to_vec(),
+            value: value.to_vec(),
+        });
+        self
+    }
+
+    // fails with VariableTooBig or SectionTooBig if things don't fit
+    pub fn build(&self) -> Result<Vec<u8>> {
+        if !self.common_size.is_multiple_of(16) || !self.system_size.is_multiple_of(16) {
+            return Err(Error::InvalidLayout("section sizes are multiples of 16"));
+        }
+        let banks = self.bank_count()?;
+        match self.version {
+            Version::V1V2 => v1v2::build(self.common_size, self.system_size, &self.variables),
+            Version::V3 => v3::build(banks, self.common_size, self.system_size, &self.variables),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvram_parse;
+
+    #[test]
+    fn test_build_parses() -> Result<()> {
+        for version in [Version::V1V2, Version::V3] {
+            let image = NvramBuilder::new(version)
+                .variable(VarType::Common, b"boot-args", b"debug")
+                .variable(VarType::System, b"foo", b"bar")
+                .build()?;
+            let nv = nvram_parse(&image)?;
+            assert_eq!(nv.version(), version);
+            assert_eq!(nv.serialize()?, image);
+            let part = nv.bank(nv.active_bank()).unwrap();
+            let var = part.get_variable(b"boot-args", VarType::Common).unwrap();
+            assert_eq!(&*var.value(), b"debug");
+            assert!(part.get_variable(b"foo", VarType::System).is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_too_small() {
+        let builder = NvramBuilder::new(Version::V1V2).system_size(32).variable(
+            VarType::System,
+            b"foo",
+            b"a long enough value",
+        );
+        assert!(matches!(
+            builder.build(),
+            Err(crate::Error::VariableTooBig(_))
+        ));
+        let builder = NvramBuilder::new(Version::V1V2).common_size(0x10000);
+        assert!(matches!(builder.build(), Err(crate::Error::SectionTooBig)));
+        let builder = NvramBuilder::new(Version::V3).common_size(0x10000);
+        assert!(matches!(builder.build(), Err(crate::Error::SectionTooBig)));
+    }
+
+    #[test]
+    fn test_build_layout() -> Result<()> {
+        let image = NvramBuilder::new(Version::V3).image_size(0x40000).build()?;
+        assert_eq!(image.len(), 0x40000);
+        assert_eq!(nvram_parse(&image)?.bank_states().len(), 4);
+        let image = NvramBuilder::new(Version::V1V2)
+            .common_size(0x8000)
+            .system_size(0x2000)
+            .build()?;
+        let nv = nvram_parse(&image)?;
+        let part = nv.bank(0).unwrap();
+        assert_eq!(part.capacity(VarType::System), 0x2000);
+        let image = NvramBuilder::new(Version::V3)
+            .banks(3)
+            .image_size(0x30000)
+            .build()?;
+        assert_eq!(image.len(), 0x30000);
+        for builder in [
+            NvramBuilder::new(Version::V1V2).image_size(0x30000),
+            NvramBuilder::new(Version::V1V2).banks(3),
+            NvramBuilder::new(Version::V3).image_size(0x18000),
+            NvramBuilder::new(Version::V3).image_size(0x200000),
+            NvramBuilder::new(Version::V3).banks(0),
+            NvramBuilder::new(Version::V3).banks(99),
+            NvramBuilder::new(Version::V3).banks(2).image_size(0x30000),
+            NvramBuilder::new(Version::V3).system_size(0x4001),
+        ] {
+            assert!(
+                matches!(builder.build(), Err(Error::InvalidLayout(_))),
+                "{:?}",
+                builder
+            );
+        }
+        Ok(())
+    }
+}
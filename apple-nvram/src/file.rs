@@ -0,0 +1,28 @@
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+};
+
+use crate::NvramWriter;
+
+// for nvram images in regular files, there is nothing to erase and writes
+// simply replace what was there
+pub struct FileWriter {
+    file: File,
+}
+
+impl FileWriter {
+    pub fn new(file: File) -> FileWriter {
+        FileWriter { file }
+    }
+}
+
+impl NvramWriter for FileWriter {
+    fn erase_if_needed(&mut self, _offset: u32, _size: usize) {}
+
+    fn write_all(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(buf)?;
+        self.file.flush()
+    }
+}
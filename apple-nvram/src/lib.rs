@@ -1,13 +1,43 @@
 // SPDX-License-Identifier: MIT
-use std::{
-    borrow::Cow,
-    fmt::{Debug, Display, Formatter},
-};
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
 
+use alloc::{borrow::Cow, boxed::Box, string::String, vec::Vec};
+use core::fmt::{Debug, Display, Formatter};
+
+// parser diagnostics go through the log crate with the "log" feature, and
+// nowhere without it
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        log::debug!($($arg)*);
+        #[cfg(not(feature = "log"))]
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        log::warn!($($arg)*);
+        #[cfg(not(feature = "log"))]
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod file;
+// the erase ioctls only exist on linux, parsing and the other writers
+// build anywhere
+#[cfg(all(feature = "std", target_os = "linux"))]
 pub mod mtd;
 
 pub mod v1v2;
 pub mod v3;
+pub mod vec;
+#[cfg(feature = "std")]
+pub mod verify;
 
 fn chrp_checksum_add(lhs: u8, rhs: u8) -> u8 {
     let (out, carry) = lhs.overflowing_add(rhs);
@@ -40,58 +70,940 @@ fn slice_find<T: PartialEq<T>>(ts: &[T], t: &T) -> Option<usize> {
     ret
 }
 
+// what Display prints for names and values: printable ascii as is, and
+// every other byte, '%' itself and anything in `reserved`, as %XX, which is
+// exactly what unescape_value decodes again
+fn escape(val: &[u8], reserved: &[u8]) -> String {
+    let mut ret = String::new();
+    for &c in val {
+        if (c.is_ascii_graphic() || c == b' ') && c != b'%' && !reserved.contains(&c) {
+            ret.push(c as char);
+        } else {
+            ret.push_str(&alloc::format!("%{c:02x}"));
+        }
+    }
+    ret
+}
+
+// the escaping Display uses for values, printable ascii as is and every
+// other byte, '%' included, as %XX
+pub fn escape_value(val: &[u8]) -> String {
+    escape(val, b"")
+}
+
+// reverses escape_value, anything that isn't a %XX escape is taken as is,
+// so plain text needs no escaping at all
+pub fn unescape_value(val: &str) -> Result<Vec<u8>> {
+    let val = val.as_bytes();
+    let mut ret = Vec::with_capacity(val.len());
+    let mut i = 0;
+    while i < val.len() {
+        if val[i] == b'%' {
+            let hex = val
+                .get(i + 1..i + 3)
+                .and_then(|h| core::str::from_utf8(h).ok())
+                .ok_or(Error::InvalidEscape)?;
+            // from_str_radix would take a sign as well
+            if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::InvalidEscape);
+            }
+            ret.push(u8::from_str_radix(hex, 16).map_err(|_| Error::InvalidEscape)?);
+            i += 3;
+        } else {
+            ret.push(val[i]);
+            i += 1;
+        }
+    }
+    Ok(ret)
+}
+
 #[derive(Debug)]
 pub enum Error {
     ParseError,
     SectionTooBig,
-    ApplyError(std::io::Error),
+    // how many bytes over the limit the variable would have gone
+    VariableTooBig(usize),
+    ApplyError(WriteError),
+    VariableNotFound,
+    // a % not followed by two hex digits
+    InvalidEscape,
+    // nvram_parse_strict found something nvram_parse would have got past
+    Anomaly(Anomaly),
+    // sizes given to the builder that don't make an image, and why
+    InvalidLayout(&'static str),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Anomaly {
+    // a bank that didn't parse, index
+    CorruptBank(usize),
+    // names stored more than once in a bank, index and how many
+    Duplicates(usize, usize),
+    // the first byte of what should be unused space that isn't, offset in
+    // the image
+    StrayData(usize),
+    // where the banks end, the image goes on past it
+    TrailingData(usize),
+}
+
+impl Display for Anomaly {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match *self {
+            Anomaly::CorruptBank(i) => write!(f, "bank {} is corrupt", i),
+            Anomaly::Duplicates(i, n) => write!(f, "bank {} has {} duplicate variables", i, n),
+            Anomaly::StrayData(offset) => write!(f, "unused space at {:#x} isn't empty", offset),
+            Anomaly::TrailingData(end) => {
+                write!(f, "the image goes on past its banks at {:#x}", end)
+            }
+        }
+    }
 }
 
-type Result<T> = std::result::Result<T, Error>;
+// what a NvramWriter fails with
+#[cfg(feature = "std")]
+pub type WriteError = std::io::Error;
+#[cfg(not(feature = "std"))]
+#[derive(Debug)]
+pub struct WriteError;
 
-#[derive(Clone, Copy, PartialEq)]
+#[cfg(not(feature = "std"))]
+impl Display for WriteError {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "write error")
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            Error::ParseError => write!(f, "not a valid nvram image"),
+            Error::SectionTooBig => write!(f, "variables don't fit in the nvram partition"),
+            Error::VariableTooBig(over) => {
+                write!(
+                    f,
+                    "variable doesn't fit in the nvram partition by {} bytes",
+                    over
+                )
+            }
+            Error::ApplyError(e) => write!(f, "failed to write nvram: {}", e),
+            Error::VariableNotFound => write!(f, "variable not found"),
+            Error::InvalidEscape => write!(f, "invalid %XX escape"),
+            Error::Anomaly(a) => write!(f, "{}", a),
+            Error::InvalidLayout(why) => write!(f, "invalid nvram layout: {}", why),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ApplyError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub enum VarType {
     Common,
     System,
 }
 
 impl Display for VarType {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        match self {
-            &VarType::Common => write!(f, "common"),
-            &VarType::System => write!(f, "system"),
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match *self {
+            VarType::Common => write!(f, "common"),
+            VarType::System => write!(f, "system"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Version {
+    V1V2,
+    V3,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match *self {
+            Version::V1V2 => write!(f, "v1v2"),
+            Version::V3 => write!(f, "v3"),
         }
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BankState {
+    Valid,
+    Invalid,
+    Empty,
+}
+
+impl Display for BankState {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match *self {
+            BankState::Valid => write!(f, "valid"),
+            BankState::Invalid => write!(f, "invalid"),
+            BankState::Empty => write!(f, "empty"),
+        }
+    }
+}
+
+// why parsing settled on the active bank
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ActiveReason {
+    // none of the other banks are valid
+    OnlyValid,
+    // it has the highest generation of the valid banks
+    Newest,
+    // several valid banks share the highest generation, the last one wins
+    // on v1v2 and the first on v3
+    Tie,
+}
+
+impl Display for ActiveReason {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match *self {
+            ActiveReason::OnlyValid => write!(f, "the only valid bank"),
+            ActiveReason::Newest => write!(f, "highest generation"),
+            ActiveReason::Tie => write!(f, "tied for highest generation"),
+        }
+    }
+}
+
+// picks the valid bank with the highest generation, None if there is none.
+// A tie goes to the last of them with `last_wins`, the first otherwise
+pub(crate) fn select_active(
+    generations: &[Option<u32>],
+    last_wins: bool,
+) -> Option<(usize, ActiveReason)> {
+    let valid = generations
+        .iter()
+        .enumerate()
+        .filter_map(|(i, g)| Some((i, (*g)?)));
+    // max_by_key keeps the last of equal elements
+    let (active, max) = match last_wins {
+        true => valid.max_by_key(|&(_, g)| g)?,
+        false => valid.rev().max_by_key(|&(_, g)| g)?,
+    };
+    let valid = generations.iter().flatten().count();
+    let newest = generations.iter().flatten().filter(|&&g| g == max).count();
+    let reason = if valid == 1 {
+        ActiveReason::OnlyValid
+    } else if newest == 1 {
+        ActiveReason::Newest
+    } else {
+        ActiveReason::Tie
+    };
+    debug!("active bank {}: {}, generation {}", active, reason, max);
+    Some((active, reason))
+}
+
+// a checksum at `offset` in the image that was rewritten from `old` to `new`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChecksumFix {
+    pub offset: usize,
+    pub old: u32,
+    pub new: u32,
+}
+
+// recompute every checksum in the raw image that doesn't match its data,
+// banks with correct checksums are left untouched. common and system use
+// the same scheme in both formats: in v1v2 one adler32 covers the bank and
+// each section header has a chrp checksum, in v3 every variable has its
+// own crc32 whichever guid it's under
+pub fn nvram_fix_checksums(nvr: &mut [u8]) -> Vec<ChecksumFix> {
+    if v3::is_v3(nvr) {
+        v3::fix_checksums(nvr)
+    } else {
+        v1v2::fix_checksums(nvr)
+    }
+}
+
+// where two images first part ways, with the byte each has there. only the
+// length they share is compared, a size difference is for the caller to check
+pub fn first_diff(a: &[u8], b: &[u8]) -> Option<(usize, u8, u8)> {
+    let i = a.iter().zip(b).position(|(x, y)| x != y)?;
+    Some((i, a[i], b[i]))
+}
+
+// a look at the first bytes of a device, enough to tell an nvram partition
+// from the others without reading all of it
+pub fn is_nvram_header(header: &[u8]) -> bool {
+    v3::is_v3(header) || v1v2::CHRPHeader::parse(header).is_ok_and(|h| h.name == b"nvram")
+}
+
 pub fn nvram_parse<'a>(nvr: &'a [u8]) -> Result<Box<dyn Nvram<'a> + 'a>> {
     match (v3::Nvram::parse(nvr), v1v2::Nvram::parse(nvr)) {
-        (Ok(nvram_v3), Err(_)) => Ok(Box::new(nvram_v3)),
-        (Err(_), Ok(nvram_v1v2)) => Ok(Box::new(nvram_v1v2)),
-        _ => Err(Error::ParseError),
+        (Ok(nvram_v3), Err(_)) => {
+            debug!("found a v3 image");
+            Ok(Box::new(nvram_v3))
+        }
+        (Err(_), Ok(nvram_v1v2)) => {
+            debug!("found a v1v2 image");
+            Ok(Box::new(nvram_v1v2))
+        }
+        (Ok(_), Ok(_)) => {
+            warn!("image parses as both v1v2 and v3");
+            Err(Error::ParseError)
+        }
+        (Err(_), Err(_)) => {
+            debug!("image is neither v1v2 nor v3");
+            Err(Error::ParseError)
+        }
+    }
+}
+
+// nvram_parse reads whatever bank is still valid and skips over whatever
+// else it doesn't expect, this fails on the first such thing instead
+pub fn nvram_parse_strict<'a>(nvr: &'a [u8]) -> Result<Box<dyn Nvram<'a> + 'a>> {
+    let nv = nvram_parse(nvr)?;
+    match anomalies(&*nv, nvr.len())?.first() {
+        Some(&a) => Err(Error::Anomaly(a)),
+        None => Ok(nv),
+    }
+}
+
+// in image order, as far as that goes
+fn anomalies(nv: &dyn Nvram<'_>, len: usize) -> Result<Vec<Anomaly>> {
+    let mut ret = Vec::new();
+    for (i, state) in nv.bank_states().into_iter().enumerate() {
+        if state == BankState::Invalid {
+            ret.push(Anomaly::CorruptBank(i));
+        }
+        let Some(part) = nv.bank(i) else { continue };
+        if part.duplicates() > 0 {
+            ret.push(Anomaly::Duplicates(i, part.duplicates()));
+        }
+        if let Some(offset) = part.stray_data() {
+            ret.push(Anomaly::StrayData(offset));
+        }
+    }
+    // the serializer writes out every bank and stops there
+    let end = nv.serialize()?.len();
+    if end < len {
+        ret.push(Anomaly::TrailingData(end));
+    }
+    Ok(ret)
+}
+
+// an image that keeps its own bytes, for holding on to one without also
+// holding on to the buffer it came from. Every access parses it again, which
+// is cheap next to reading the device in the first place
+#[derive(Clone, Debug)]
+pub struct OwnedNvram {
+    data: Vec<u8>,
+}
+
+impl OwnedNvram {
+    // a blank image with no variables, the sizes are checked as the
+    // builder checks them
+    pub fn empty(
+        version: Version,
+        common_size: usize,
+        system_size: usize,
+        image_size: usize,
+    ) -> Result<OwnedNvram> {
+        let data = builder::NvramBuilder::new(version)
+            .common_size(common_size)
+            .system_size(system_size)
+            .image_size(image_size)
+            .build()?;
+        Ok(OwnedNvram { data })
+    }
+
+    pub fn nvram(&self) -> Result<Box<dyn Nvram<'_> + '_>> {
+        nvram_parse(&self.data)
+    }
+
+    // the changes f makes are kept as if they had been applied
+    pub fn modify<R, F>(&mut self, f: F) -> Result<R>
+    where
+        F: for<'a> FnOnce(&mut (dyn Nvram<'a> + 'a)) -> Result<R>,
+    {
+        let mut nv = nvram_parse(&self.data)?;
+        let ret = f(&mut *nv)?;
+        let data = nv.serialize()?;
+        drop(nv);
+        self.data = data;
+        Ok(ret)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
     }
 }
 
+pub fn nvram_parse_owned(data: Vec<u8>) -> Result<OwnedNvram> {
+    nvram_parse(&data)?;
+    Ok(OwnedNvram { data })
+}
+
+// where apply() sends its output, implemented by MtdWriter for mtd devices,
+// FileWriter for image files and VecWriter for keeping it in memory
 pub trait NvramWriter {
     fn erase_if_needed(&mut self, offset: u32, size: usize);
-    fn write_all(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()>;
+    fn write_all(&mut self, offset: u32, buf: &[u8]) -> core::result::Result<(), WriteError>;
 }
 
 pub trait Nvram<'a> {
+    fn version(&self) -> Version;
+    // what Clone for Box<dyn Nvram> goes through
+    fn boxed_clone(&self) -> Box<dyn Nvram<'a> + 'a>;
     fn prepare_for_write(&mut self);
     fn active_part_mut(&mut self) -> &mut dyn Partition<'a>;
+    // of the active bank
+    fn generation(&self) -> u32 {
+        self.bank(self.active_bank()).map_or(0, |p| p.generation())
+    }
+    // what apply() writes as the generation instead of the next one, before
+    // or after prepare_for_write(). Firmware boots from the valid bank with
+    // the highest generation, so anything not above the other banks' can
+    // leave it reading an older one, or the wrong one out of a tie
+    fn set_generation(&mut self, generation: u32);
+    // index of the active partition within partitions()
+    fn active_index(&self) -> usize;
+    // state of every bank in the image, including ones that failed to parse
+    // and are therefore missing from partitions()
+    fn bank_states(&self) -> Vec<BankState>;
+    // index of the active bank within bank_states()
+    fn active_bank(&self) -> usize;
+    // why active_bank() was picked when the image was parsed
+    fn active_reason(&self) -> ActiveReason;
+    // some bank failed to parse and was skipped over
+    fn recovered(&self) -> bool {
+        self.bank_states().contains(&BankState::Invalid)
+    }
+    // the partition in a bank, None unless the bank is valid
+    fn bank(&self, index: usize) -> Option<&dyn Partition<'a>>;
+    // the parsed partitions, in bank order
     fn partitions(&self) -> Box<dyn Iterator<Item = &dyn Partition<'a>> + '_>;
+    // same order as partitions(), only changes to the active one are
+    // guaranteed to be written by apply()
+    fn partitions_mut(&mut self) -> Box<dyn Iterator<Item = &mut dyn Partition<'a>> + '_>;
+    // the whole image as apply() would leave it, without writing anything
     fn serialize(&self) -> Result<Vec<u8>>;
-    fn apply(&mut self, w: &mut dyn NvramWriter) -> Result<()>;
+    // returns how many bytes were handed to the writer
+    fn apply(&mut self, w: &mut dyn NvramWriter) -> Result<usize>;
 }
 
+// a snapshot to compare against or to try changes on, the image it borrows
+// from is shared
+impl<'a> Clone for Box<dyn Nvram<'a> + 'a> {
+    fn clone(&self) -> Self {
+        self.boxed_clone()
+    }
+}
+
+// what Partition::iter yields
+pub type NameValue<'a> = (&'a [u8], Cow<'a, [u8]>);
+
 pub trait Partition<'a>: Display {
+    // bumped on every write, the highest one is the active partition
+    fn generation(&self) -> u32;
     fn variables(&self) -> Box<dyn Iterator<Item = &dyn Variable<'a>> + '_>;
+    // names of the variables of one type, their values are left alone
+    fn variable_names<'s>(&'s self, typ: VarType) -> Box<dyn Iterator<Item = &'a [u8]> + 's>
+    where
+        'a: 's,
+    {
+        Box::new(
+            self.variables()
+                .filter(move |v| v.typ() == typ)
+                .map(|v| v.name()),
+        )
+    }
+    // name and value pairs of one type, values are only copied where the
+    // format stores them encoded
+    fn iter<'s>(&'s self, typ: VarType) -> Box<dyn Iterator<Item = NameValue<'a>> + 's>
+    where
+        'a: 's,
+    {
+        Box::new(
+            self.variables()
+                .filter(move |v| v.typ() == typ)
+                .map(|v| (v.name(), v.value())),
+        )
+    }
     fn get_variable(&self, key: &'a [u8], typ: VarType) -> Option<&dyn Variable<'a>>;
-    fn insert_variable(&mut self, key: &'a [u8], value: Cow<'a, [u8]>, typ: VarType);
+    fn contains(&self, key: &'a [u8], typ: VarType) -> bool {
+        self.get_variable(key, typ).is_some()
+    }
+    // get_variable for callers that want to bail out with ?
+    fn try_get_variable(&self, key: &'a [u8], typ: VarType) -> Result<&dyn Variable<'a>> {
+        self.get_variable(key, typ).ok_or(Error::VariableNotFound)
+    }
+    // fails with VariableTooBig and leaves the partition alone if the value
+    // doesn't fit in what's left of its section
+    fn insert_variable(&mut self, key: &'a [u8], value: Cow<'a, [u8]>, typ: VarType) -> Result<()>;
     fn remove_variable(&mut self, key: &'a [u8], typ: VarType);
+    // remove every variable of the given type
+    fn clear(&mut self, typ: VarType);
+    // space available for variables of the given type
+    fn capacity(&self, typ: VarType) -> usize;
+    // space the variables of the given type take up, headers included
+    fn used_space(&self, typ: VarType) -> usize;
+    // where the partition starts in the image and how many bytes of it the
+    // serializer writes
+    fn offset(&self) -> usize;
+    fn size(&self) -> usize;
+    // entries dropped on parse because a later one had the same name
+    fn duplicates(&self) -> usize;
+    // where the space after the variables stops looking erased, if it
+    // does, as an offset in the image
+    fn stray_data(&self) -> Option<usize>;
+    fn free_space(&self, typ: VarType) -> usize {
+        self.capacity(typ).saturating_sub(self.used_space(typ))
+    }
 }
 
 pub trait Variable<'a>: Display {
+    fn name(&self) -> &'a [u8];
+    fn typ(&self) -> VarType;
+    // borrowed from the parsed image unless it had to be decoded
     fn value(&self) -> Cow<'a, [u8]>;
 }
+
+// what variable equality is based on, so that variables compare by their
+// decoded value however the format happens to store it
+fn variable_key<'a>(var: &dyn Variable<'a>) -> (VarType, &'a [u8], Cow<'a, [u8]>) {
+    (var.typ(), var.name(), var.value())
+}
+
+// partitions are equal when they hold the same variables, in any order and
+// regardless of generation or where they are in the image
+fn same_variables<'a>(a: &dyn Partition<'a>, b: &dyn Partition<'a>) -> bool {
+    let sorted = |p: &dyn Partition<'a>| {
+        let mut vars: Vec<_> = p.variables().map(variable_key).collect();
+        vars.sort();
+        vars
+    };
+    sorted(a) == sorted(b)
+}
+
+// a variable that doesn't borrow from the image it came from
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct OwnedVariable {
+    pub typ: VarType,
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl<'a> dyn Variable<'a> + '_ {
+    // the value as text, ignoring NUL terminators, None if it isn't utf-8
+    // or has NULs before the end
+    pub fn as_str(&self) -> Option<Cow<'a, str>> {
+        let trimmed = |v: &[u8]| v.len() - v.iter().rev().take_while(|&&b| b == 0).count();
+        match self.value() {
+            Cow::Borrowed(v) => {
+                let v = &v[..trimmed(v)];
+                if v.contains(&0) {
+                    return None;
+                }
+                core::str::from_utf8(v).ok().map(Cow::Borrowed)
+            }
+            Cow::Owned(mut v) => {
+                v.truncate(trimmed(&v));
+                if v.contains(&0) {
+                    return None;
+                }
+                String::from_utf8(v).ok().map(Cow::Owned)
+            }
+        }
+    }
+
+    // exactly four bytes, little endian
+    pub fn as_u32_le(&self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.value().as_ref().try_into().ok()?))
+    }
+
+    // "true"/"false" the way macOS stores them, or a single 0/1 byte
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.value().as_ref() {
+            [0] => return Some(false),
+            [1] => return Some(true),
+            _ => {}
+        }
+        match self.as_str()?.as_ref() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn to_owned_variable(&self) -> OwnedVariable {
+        OwnedVariable {
+            typ: self.typ(),
+            name: self.name().to_vec(),
+            value: self.value().into_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_matches_apply() {
+        for version in [Version::V1V2, Version::V3] {
+            let data = builder::NvramBuilder::new(version)
+                .variable(VarType::System, b"x", b"1")
+                .build()
+                .unwrap();
+            let mut nv = nvram_parse(&data).unwrap();
+            nv.prepare_for_write();
+            nv.active_part_mut()
+                .insert_variable(b"a", Cow::Borrowed(b"b"), VarType::Common)
+                .unwrap();
+            let mut w = vec::VecWriter::new(data.clone());
+            nv.apply(&mut w).unwrap();
+            assert_eq!(nv.serialize().unwrap(), w.bytes(), "{}", version);
+        }
+    }
+
+    #[test]
+    fn test_system_edit_checksums() {
+        for version in [Version::V1V2, Version::V3] {
+            let data = builder::NvramBuilder::new(version)
+                .variable(VarType::Common, b"c", b"1")
+                .variable(VarType::System, b"s", b"1")
+                .build()
+                .unwrap();
+            let mut nv = nvram_parse(&data).unwrap();
+            nv.prepare_for_write();
+            let part = nv.active_part_mut();
+            part.insert_variable(b"s", Cow::Borrowed(b"2"), VarType::System)
+                .unwrap();
+            part.insert_variable(b"t", Cow::Borrowed(b"\0\xff"), VarType::System)
+                .unwrap();
+            let mut image = nv.serialize().unwrap();
+            assert_eq!(nvram_fix_checksums(&mut image), [], "{}", version);
+            let mut nv = nvram_parse_strict(&image).unwrap();
+            let part = nv.active_part_mut();
+            let value = |name| part.get_variable(name, VarType::System).unwrap().value();
+            assert_eq!(value(b"s"), &b"2"[..]);
+            assert_eq!(value(b"t"), &b"\0\xff"[..]);
+        }
+    }
+
+    #[test]
+    fn test_clone() {
+        let data = builder::NvramBuilder::new(Version::V3).build().unwrap();
+        let mut nv = nvram_parse(&data).unwrap();
+        let snapshot = nv.clone();
+        nv.active_part_mut()
+            .insert_variable(b"a", Cow::Borrowed(b"b"), VarType::Common)
+            .unwrap();
+        assert_eq!(nv.active_part_mut().variables().count(), 1);
+        assert_eq!(snapshot.bank(0).unwrap().variables().count(), 0);
+    }
+
+    #[test]
+    fn test_escape_round_trip() {
+        assert_eq!(escape_value(b"a b%\0\xff"), "a b%25%00%ff");
+        assert_eq!(unescape_value("x%3D%3dy").unwrap(), b"x==y");
+        for bad in ["%", "%f", "%zz", "%+1", "a%\u{e9}"] {
+            assert!(matches!(unescape_value(bad), Err(Error::InvalidEscape)));
+        }
+        // arbitrary byte strings, from a fixed xorshift so failures repeat
+        let mut state = 0x2545_f491_u32;
+        for len in 0..300 {
+            let val: Vec<u8> = (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect();
+            assert_eq!(unescape_value(&escape_value(&val)).unwrap(), val);
+        }
+    }
+
+    #[test]
+    fn test_iter() {
+        use alloc::collections::BTreeMap;
+        for version in [Version::V1V2, Version::V3] {
+            let data = builder::NvramBuilder::new(version)
+                .variable(VarType::Common, b"b", b"2\0\0")
+                .variable(VarType::Common, b"a", b"1")
+                .variable(VarType::System, b"c", b"3")
+                .build()
+                .unwrap();
+            let mut nv = nvram_parse(&data).unwrap();
+            let common: BTreeMap<_, _> = nv.active_part_mut().iter(VarType::Common).collect();
+            assert_eq!(common.len(), 2);
+            assert_eq!(common[&b"a"[..]], &b"1"[..]);
+            assert_eq!(common[&b"b"[..]], &b"2\0\0"[..]);
+        }
+    }
+
+    #[test]
+    fn test_is_nvram_header() {
+        for version in [Version::V1V2, Version::V3] {
+            let data = builder::NvramBuilder::new(version).build().unwrap();
+            assert!(is_nvram_header(&data[..16]));
+        }
+        assert!(!is_nvram_header(&[0xFF; 16]));
+        assert!(!is_nvram_header(b"nvram"));
+    }
+
+    #[test]
+    fn test_first_diff() {
+        assert_eq!(first_diff(b"abc", b"abc"), None);
+        assert_eq!(first_diff(b"abc", b"abx"), Some((2, b'c', b'x')));
+        assert_eq!(first_diff(b"ab", b"abc"), None);
+    }
+
+    #[test]
+    fn test_parse_owned() {
+        fn load() -> OwnedNvram {
+            let data = builder::NvramBuilder::new(Version::V3)
+                .variable(VarType::Common, b"a", b"1")
+                .build()
+                .unwrap();
+            nvram_parse_owned(data).unwrap()
+        }
+        let mut owned = load();
+        owned
+            .modify(|nv| {
+                nv.prepare_for_write();
+                nv.active_part_mut()
+                    .insert_variable(b"b", Cow::Borrowed(b"2"), VarType::Common)
+            })
+            .unwrap();
+        let mut nv = owned.nvram().unwrap();
+        let part = nv.active_part_mut();
+        assert!(part.contains(b"a", VarType::Common));
+        assert!(part.contains(b"b", VarType::Common));
+        assert!(nvram_parse_owned(Vec::from([0; 16])).is_err());
+    }
+
+    #[test]
+    fn test_empty() {
+        for (version, common, system, size) in [
+            (Version::V1V2, 0x8000, 0x1000, 0x20000),
+            (Version::V3, 0xC000, 0x4000, 0x30000),
+        ] {
+            let owned = OwnedNvram::empty(version, common, system, size).unwrap();
+            assert_eq!(owned.as_bytes().len(), size);
+            let nv = nvram_parse(owned.as_bytes()).unwrap();
+            assert_eq!(nv.version(), version);
+            assert_eq!(nv.serialize().unwrap(), owned.as_bytes());
+            for part in nv.partitions() {
+                assert_eq!(part.iter(VarType::Common).count(), 0);
+                assert_eq!(part.iter(VarType::System).count(), 0);
+            }
+        }
+        assert!(OwnedNvram::empty(Version::V1V2, 0x10000, 0x1000, 0x20000).is_err());
+    }
+
+    #[test]
+    fn test_strict_anomalies() {
+        for version in [Version::V1V2, Version::V3] {
+            let data = builder::NvramBuilder::new(version)
+                .variable(VarType::Common, b"a", b"1")
+                .build()
+                .unwrap();
+            assert!(nvram_parse_strict(&data).is_ok());
+            let strict = |data: &[u8]| match nvram_parse_strict(data) {
+                Err(Error::Anomaly(a)) => Some(a),
+                _ => None,
+            };
+            // unused space in the first bank, with checksums that still match
+            let mut stray = data.clone();
+            stray[0x8000] = 0x42;
+            nvram_fix_checksums(&mut stray);
+            assert!(nvram_parse(&stray).is_ok());
+            assert_eq!(strict(&stray), Some(Anomaly::StrayData(0x8000)));
+            let mut longer = data.clone();
+            longer.extend_from_slice(&[0xFF; 16]);
+            assert_eq!(strict(&longer), Some(Anomaly::TrailingData(data.len())));
+        }
+    }
+
+    #[test]
+    fn test_set_generation() {
+        for version in [Version::V1V2, Version::V3] {
+            for before_prepare in [false, true] {
+                let data = builder::NvramBuilder::new(version).build().unwrap();
+                let mut nv = nvram_parse(&data).unwrap();
+                let before: Vec<_> = (0..nv.bank_states().len())
+                    .map(|i| nv.bank(i).map(|p| p.generation()))
+                    .collect();
+                if before_prepare {
+                    nv.set_generation(100);
+                }
+                nv.prepare_for_write();
+                if !before_prepare {
+                    nv.set_generation(100);
+                }
+                nv.active_part_mut()
+                    .insert_variable(b"a", Cow::Borrowed(b"1"), VarType::Common)
+                    .unwrap();
+                let mut w = vec::VecWriter::new(data.clone());
+                nv.apply(&mut w).unwrap();
+                let data = w.into_bytes();
+                let nv = nvram_parse_strict(&data).unwrap();
+                assert_eq!(nv.generation(), 100);
+                // the bank that was live before is left as it was
+                for (i, generation) in before.iter().enumerate() {
+                    if i != nv.active_bank() {
+                        assert_eq!(nv.bank(i).map(|p| p.generation()), *generation);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_generation_wraps() {
+        for version in [Version::V1V2, Version::V3] {
+            let mut data = builder::NvramBuilder::new(version).build().unwrap();
+            for (i, value) in [b'1', b'2'].into_iter().enumerate() {
+                let mut nv = nvram_parse(&data).unwrap();
+                if i == 0 {
+                    nv.set_generation(u32::MAX);
+                }
+                nv.prepare_for_write();
+                // big enough that v3 can't replace it in place either
+                nv.active_part_mut()
+                    .insert_variable(b"a", Cow::Owned(alloc::vec![value; 40000]), VarType::Common)
+                    .unwrap();
+                let mut w = vec::VecWriter::new(data.clone());
+                nv.apply(&mut w).unwrap();
+                drop(nv);
+                data = w.into_bytes();
+            }
+            // the bank after u32::MAX is 0, and loses to it
+            let nv = nvram_parse_strict(&data).unwrap();
+            assert_eq!(nv.generation(), u32::MAX, "{}", version);
+            let other = 1 - nv.active_bank();
+            assert_eq!(nv.bank(other).unwrap().generation(), 0, "{}", version);
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let data = builder::NvramBuilder::new(Version::V1V2)
+            .variable(VarType::System, b"a", b"b")
+            .build()
+            .unwrap();
+        let mut nv = nvram_parse(&data).unwrap();
+        let part = nv.active_part_mut();
+        assert!(part.contains(b"a", VarType::System));
+        assert!(!part.contains(b"a", VarType::Common));
+        assert_eq!(
+            part.try_get_variable(b"a", VarType::System)
+                .unwrap()
+                .value(),
+            &b"b"[..]
+        );
+        assert!(matches!(
+            part.try_get_variable(b"x", VarType::System),
+            Err(Error::VariableNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_parse_strict() {
+        let mut data = builder::NvramBuilder::new(Version::V1V2).build().unwrap();
+        assert!(!nvram_parse(&data).unwrap().recovered());
+        assert!(nvram_parse_strict(&data).is_ok());
+        // corrupt the second bank
+        data[0x10000 + 40] ^= 0xFF;
+        let nv = nvram_parse(&data).unwrap();
+        assert!(nv.recovered());
+        assert_eq!(nv.active_bank(), 0);
+        assert!(matches!(
+            nvram_parse_strict(&data),
+            Err(Error::Anomaly(Anomaly::CorruptBank(1)))
+        ));
+    }
+
+    #[test]
+    fn test_select_active() {
+        assert_eq!(select_active(&[None, None], true), None);
+        assert_eq!(
+            select_active(&[Some(7), None], true),
+            Some((0, ActiveReason::OnlyValid))
+        );
+        assert_eq!(
+            select_active(&[Some(3), Some(2), None], false),
+            Some((0, ActiveReason::Newest))
+        );
+        assert_eq!(
+            select_active(&[Some(2), Some(2)], true),
+            Some((1, ActiveReason::Tie))
+        );
+        assert_eq!(
+            select_active(&[None, Some(2), Some(2)], false),
+            Some((1, ActiveReason::Tie))
+        );
+    }
+
+    struct TestVariable(&'static [u8]);
+
+    impl Display for TestVariable {
+        fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+
+    impl Variable<'static> for TestVariable {
+        fn name(&self) -> &'static [u8] {
+            b"test-variable"
+        }
+
+        fn typ(&self) -> VarType {
+            VarType::Common
+        }
+
+        fn value(&self) -> Cow<'static, [u8]> {
+            Cow::Borrowed(self.0)
+        }
+    }
+
+    fn var(value: &'static [u8]) -> Box<dyn Variable<'static>> {
+        Box::new(TestVariable(value))
+    }
+
+    #[test]
+    fn test_as_str() {
+        assert_eq!(var(b"debug").as_str().as_deref(), Some("debug"));
+        assert_eq!(var(b"debug\0\0").as_str().as_deref(), Some("debug"));
+        assert_eq!(var(b"").as_str().as_deref(), Some(""));
+        assert_eq!(var(b"a\0b").as_str(), None);
+        assert_eq!(var(b"\xff").as_str(), None);
+    }
+
+    #[test]
+    fn test_as_u32_le() {
+        assert_eq!(var(b"\x01\x02\0\0").as_u32_le(), Some(0x201));
+        assert_eq!(var(b"\x01\x02\0").as_u32_le(), None);
+    }
+
+    #[test]
+    fn test_as_bool() {
+        assert_eq!(var(b"true").as_bool(), Some(true));
+        assert_eq!(var(b"false\0").as_bool(), Some(false));
+        assert_eq!(var(b"\x01").as_bool(), Some(true));
+        assert_eq!(var(b"yes").as_bool(), None);
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(b"boot-args", b"="), "boot-args");
+        assert_eq!(escape(b"a=b%\xff\n", b"="), "a%3db%25%ff%0a");
+        assert_eq!(escape(b"a=b c", b""), "a=b c");
+    }
+}
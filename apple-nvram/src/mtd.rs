@@ -50,6 +50,20 @@ pub struct MtdInfoUser {
 nix::ioctl_write_ptr!(mtd_mem_erase, b'M', 2, EraseInfoUser);
 nix::ioctl_read!(mtd_mem_get_info, b'M', 1, MtdInfoUser);
 
+// None if the file isn't an mtd device
+pub fn erase_size(file: &File) -> Option<usize> {
+    let mut info = MtdInfoUser::default();
+    unsafe { mtd_mem_get_info(file.as_raw_fd(), &mut info) }.ok()?;
+    Some(info.erasesize as usize)
+}
+
+// the whole device, None if the file isn't an mtd device
+pub fn size(file: &File) -> Option<u64> {
+    let mut info = MtdInfoUser::default();
+    unsafe { mtd_mem_get_info(file.as_raw_fd(), &mut info) }.ok()?;
+    Some(info.size as u64)
+}
+
 fn erase_if_needed(file: &File, offset: u32, size: usize) {
     if unsafe { mtd_mem_get_info(file.as_raw_fd(), &mut MtdInfoUser::default()) }.is_err() {
         return;
@@ -1,10 +1,10 @@
-use std::{
-    borrow::Cow,
-    collections::HashMap,
+use alloc::{borrow::Cow, boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use core::{
     fmt::{Debug, Display, Formatter},
+    hash::{Hash, Hasher},
 };
 
-use crate::{chrp_checksum_add, slice_find, slice_rstrip, Error, Result, VarType};
+use crate::{chrp_checksum_add, slice_find, slice_rstrip, BankState, Error, Result, VarType};
 
 pub struct UnescapeVal<I> {
     inner: I,
@@ -31,24 +31,43 @@ where
 {
     type Item = u8;
     fn next(&mut self) -> Option<u8> {
-        if self.remaining != 0 {
-            self.remaining -= 1;
-            return Some(self.esc_out);
-        }
-        if let Some(n) = self.inner.next() {
+        loop {
+            if self.remaining != 0 {
+                self.remaining -= 1;
+                return Some(self.esc_out);
+            }
+            let n = self.inner.next()?;
             if n != 0xFF {
                 return Some(n);
             }
             let count = self.inner.next()?;
             self.esc_out = if count & 0x80 == 0 { 0 } else { 0xFF };
-            self.remaining = (count & 0x7F) - 1;
-            Some(self.esc_out)
-        } else {
-            None
+            // a run of length 0 stands for nothing at all
+            self.remaining = count & 0x7F;
         }
     }
 }
 
+// inverse of UnescapeVal: runs of up to 127 0x00 or 0xFF bytes are stored
+// as 0xFF and the length, with the top bit set for 0xFF, so that values
+// can't contain the NUL that ends them
+fn escape_val(val: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(val.len());
+    let mut rest = val;
+    while let Some(&c) = rest.first() {
+        if c != 0 && c != 0xFF {
+            ret.push(c);
+            rest = &rest[1..];
+            continue;
+        }
+        let run = rest.iter().take(0x7F).take_while(|&&b| b == c).count();
+        ret.push(0xFF);
+        ret.push(run as u8 | (c & 0x80));
+        rest = &rest[run..];
+    }
+    ret
+}
+
 #[derive(Clone)]
 pub struct CHRPHeader<'a> {
     pub name: &'a [u8],
@@ -57,7 +76,7 @@ pub struct CHRPHeader<'a> {
 }
 
 impl Debug for CHRPHeader<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("CHRPHeader")
             .field("name", &String::from_utf8_lossy(self.name).into_owned())
             .field("size", &self.size)
@@ -68,6 +87,9 @@ impl Debug for CHRPHeader<'_> {
 
 impl CHRPHeader<'_> {
     pub fn parse(nvr: &[u8]) -> Result<CHRPHeader<'_>> {
+        if nvr.len() < 16 {
+            return Err(Error::ParseError);
+        }
         let signature = nvr[0];
         let cksum = nvr[1];
         let size = u16::from_le_bytes(nvr[2..4].try_into().unwrap());
@@ -120,40 +142,72 @@ impl<'a> Variable<'a> {
     }
 }
 
+impl PartialEq for Variable<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        crate::variable_key(self) == crate::variable_key(other)
+    }
+}
+
+impl Eq for Variable<'_> {}
+
+impl Hash for Variable<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        crate::variable_key(self).hash(state)
+    }
+}
+
 impl<'a> crate::Variable<'a> for Variable<'a> {
+    fn name(&self) -> &'a [u8] {
+        self.key
+    }
+
+    fn typ(&self) -> VarType {
+        self.typ
+    }
+
     fn value(&self) -> Cow<'a, [u8]> {
-        Cow::Owned(UnescapeVal::new(self.value.iter().copied()).collect())
+        match self.value {
+            // nothing to unescape, hand out the image bytes as they are
+            Cow::Borrowed(v) if !v.contains(&0xFF) => Cow::Borrowed(v),
+            _ => Cow::Owned(UnescapeVal::new(self.value.iter().copied()).collect()),
+        }
     }
 }
 
 impl Display for Variable<'_> {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        let key = String::from_utf8_lossy(self.key);
-        let mut value = String::new();
-        for c in UnescapeVal::new(self.value.iter().copied()) {
-            if (c as char).is_ascii() && !(c as char).is_ascii_control() {
-                value.push(c as char);
-            } else {
-                value.push_str(&format!("%{c:02x}"));
-            }
-        }
-
-        let value: String = value.chars().take(128).collect();
-        write!(f, "{}:{}={}", self.typ, key, value)
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        let key = crate::escape(self.key, b"=");
+        let value: Vec<u8> = UnescapeVal::new(self.value.iter().copied()).collect();
+        write!(f, "{}:{}={}", self.typ, key, crate::escape(&value, b""))
     }
 }
 
 #[derive(Clone)]
 pub struct Section<'a> {
     pub header: CHRPHeader<'a>,
-    pub values: HashMap<&'a [u8], Variable<'a>>,
+    pub values: BTreeMap<&'a [u8], Variable<'a>>,
+    // order the variables were found in, so an untouched section is
+    // written back exactly as it was
+    order: Vec<&'a [u8]>,
+    // entries dropped on parse because a later one had the same name
+    duplicates: usize,
+    // the first byte after the variables that isn't zero padding, from the
+    // start of the section
+    stray: Option<usize>,
 }
 
 impl Section<'_> {
-    pub fn parse(mut nvr: &[u8]) -> Result<Section<'_>> {
-        let header = CHRPHeader::parse(&nvr[..16])?;
-        nvr = &nvr[16..];
-        let mut values = HashMap::new();
+    pub fn parse(nvr: &[u8]) -> Result<Section<'_>> {
+        let header = CHRPHeader::parse(nvr)?;
+        // the header counts itself, so anything under 16 bytes is bogus
+        let size = header.size as usize * 16;
+        let mut nvr = nvr.get(16..size).ok_or_else(|| {
+            warn!("v1v2: section runs past the end of the image");
+            Error::ParseError
+        })?;
+        let mut values = BTreeMap::new();
+        let mut order = Vec::new();
+        let mut duplicates = 0;
         loop {
             let zero = slice_find(nvr, &0);
             if zero.is_none() {
@@ -172,18 +226,54 @@ impl Section<'_> {
             } else {
                 VarType::System
             };
-            values.insert(key, Variable::new(key, &cand[(eq + 1)..], typ));
+            // the last entry wins, same as the firmware
+            if values
+                .insert(key, Variable::new(key, &cand[(eq + 1)..], typ))
+                .is_none()
+            {
+                order.push(key);
+            } else {
+                debug!("v1v2: duplicate variable, the last one wins");
+                duplicates += 1;
+            }
             nvr = &nvr[(zero + 1)..]
         }
-        Ok(Section { header, values })
+        let stray = nvr
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| size - nvr.len() + i);
+        if let Some(i) = stray {
+            debug!("v1v2: stray data {:#x} into the section", i);
+        }
+        Ok(Section {
+            header,
+            values,
+            order,
+            duplicates,
+            stray,
+        })
     }
     fn size_bytes(&self) -> usize {
         self.header.size as usize * 16
     }
+    // header plus a key=value\0 entry per variable
+    fn used_bytes(&self) -> usize {
+        16 + self
+            .values
+            .values()
+            .map(|v| v.key.len() + v.value.len() + 2)
+            .sum::<usize>()
+    }
     pub fn serialize(&self, v: &mut Vec<u8>) -> Result<()> {
         let start_size = v.len();
         self.header.serialize(v);
-        for val in self.values.values() {
+        // variables that were already there keep their place, new ones go last
+        let existing = self.order.iter().filter_map(|k| self.values.get(k));
+        let added = self
+            .values
+            .values()
+            .filter(|val| !self.order.contains(&val.key));
+        for val in existing.chain(added) {
             v.extend_from_slice(val.key);
             v.push(b'=');
             v.extend_from_slice(&val.value);
@@ -202,7 +292,7 @@ impl Section<'_> {
 
 struct SectionDebug<'a, 'b>(&'a Section<'b>);
 impl Debug for SectionDebug<'_, '_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         let mut m = f.debug_map();
         for v in self.0.values.values() {
             m.entry(
@@ -218,7 +308,7 @@ impl Debug for SectionDebug<'_, '_> {
 }
 
 impl Debug for Section<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Section")
             .field("header", &self.header)
             .field("values", &SectionDebug(self))
@@ -232,23 +322,36 @@ pub struct Partition<'a> {
     pub generation: u32,
     pub common: Section<'a>,
     pub system: Section<'a>,
+    // the rest of the header after the generation
+    reserved: [u8; 8],
+    // sections are written back in the order they were found
+    system_first: bool,
+    // anything between the sections and the end of the bank
+    tail: &'a [u8],
+    offset: usize,
 }
 
 impl<'a> Partition<'a> {
     pub fn parse(nvr: &[u8]) -> Result<Partition<'_>> {
-        let header = CHRPHeader::parse(&nvr[..16])?;
-        if header.name != b"nvram" {
+        let header = CHRPHeader::parse(nvr)?;
+        if header.name != b"nvram" || nvr.len() < 32 {
             return Err(Error::ParseError);
         }
         let adler = u32::from_le_bytes(nvr[16..20].try_into().unwrap());
         let generation = u32::from_le_bytes(nvr[20..24].try_into().unwrap());
         let sec1 = Section::parse(&nvr[32..])?;
-        let sec2 = Section::parse(&nvr[(32 + sec1.size_bytes())..])?;
-        let calc_adler =
-            adler32::adler32(&nvr[20..(32 + sec1.size_bytes() + sec2.size_bytes())]).unwrap();
+        let sec1_size = sec1.size_bytes();
+        let sec2 = Section::parse(&nvr[(32 + sec1_size)..])?;
+        let sec2_size = sec2.size_bytes();
+        let calc_adler = adler32(&nvr[20..(32 + sec1_size + sec2_size)]);
         if adler != calc_adler {
+            warn!(
+                "v1v2: adler32 mismatch, stored {:08x}, computed {:08x}",
+                adler, calc_adler
+            );
             return Err(Error::ParseError);
         }
+        let system_first = sec1.header.name == b"system";
         let mut com = None;
         let mut sys = None;
         if sec1.header.name == b"common" {
@@ -269,22 +372,44 @@ impl<'a> Partition<'a> {
             generation,
             common: com.unwrap(),
             system: sys.unwrap(),
+            reserved: nvr[24..32].try_into().unwrap(),
+            system_first,
+            tail: &nvr[(32 + sec1_size + sec2_size)..],
+            offset: 0,
         })
     }
     fn size_bytes(&self) -> usize {
         32 + self.common.size_bytes() + self.system.size_bytes()
     }
+
+    // end of the region covered by the adler32 checksum, checking
+    // everything but the checksum itself
+    fn checksum_end(nvr: &[u8]) -> Result<usize> {
+        let header = CHRPHeader::parse(nvr)?;
+        if header.name != b"nvram" || nvr.len() < 32 {
+            return Err(Error::ParseError);
+        }
+        let sec1 = Section::parse(&nvr[32..])?;
+        let sec2 = Section::parse(&nvr[(32 + sec1.size_bytes())..])?;
+        Ok(32 + sec1.size_bytes() + sec2.size_bytes())
+    }
     pub fn serialize(&self, v: &mut Vec<u8>) -> Result<()> {
         self.header.serialize(v);
         v.extend_from_slice(&[0; 4]);
         let adler_start = v.len();
         v.extend_from_slice(&self.generation.to_le_bytes());
-        v.extend_from_slice(&[0; 8]);
-        self.common.serialize(v)?;
-        self.system.serialize(v)?;
+        v.extend_from_slice(&self.reserved);
+        if self.system_first {
+            self.system.serialize(v)?;
+            self.common.serialize(v)?;
+        } else {
+            self.common.serialize(v)?;
+            self.system.serialize(v)?;
+        }
         let adler_end = v.len();
-        let adler = adler32::adler32(&v[adler_start..adler_end]).unwrap();
+        let adler = adler32(&v[adler_start..adler_end]);
         v[(adler_start - 4)..adler_start].copy_from_slice(&adler.to_le_bytes());
+        v.extend_from_slice(self.tail);
         Ok(())
     }
 
@@ -296,7 +421,19 @@ impl<'a> Partition<'a> {
     }
 }
 
+impl PartialEq for Partition<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        crate::same_variables(self, other)
+    }
+}
+
+impl Eq for Partition<'_> {}
+
 impl<'a> crate::Partition<'a> for Partition<'a> {
+    fn generation(&self) -> u32 {
+        self.generation
+    }
+
     fn get_variable(&self, key: &[u8], typ: VarType) -> Option<&dyn crate::Variable<'a>> {
         match typ {
             VarType::Common => self
@@ -312,13 +449,26 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
         }
     }
 
-    fn insert_variable(&mut self, key: &'a [u8], value: Cow<'a, [u8]>, typ: VarType) {
-        match typ {
+    fn insert_variable(&mut self, key: &'a [u8], value: Cow<'a, [u8]>, typ: VarType) -> Result<()> {
+        let section = match typ {
             VarType::Common => &mut self.common,
             VarType::System => &mut self.system,
+        };
+        let value = if value.iter().any(|&c| c == 0 || c == 0xFF) {
+            Cow::Owned(escape_val(&value))
+        } else {
+            value
+        };
+        let old = section
+            .values
+            .get(key)
+            .map_or(0, |v| v.key.len() + v.value.len() + 2);
+        let used = section.used_bytes() - old + key.len() + value.len() + 2;
+        if used > section.size_bytes() {
+            return Err(Error::VariableTooBig(used - section.size_bytes()));
         }
-        .values
-        .insert(key, Variable { key, value, typ });
+        section.values.insert(key, Variable { key, value, typ });
+        Ok(())
     }
 
     fn remove_variable(&mut self, key: &'a [u8], typ: VarType) {
@@ -330,13 +480,61 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
         .remove(key);
     }
 
+    fn clear(&mut self, typ: VarType) {
+        match typ {
+            VarType::Common => &mut self.common,
+            VarType::System => &mut self.system,
+        }
+        .values
+        .clear();
+    }
+
     fn variables(&self) -> Box<dyn Iterator<Item = &dyn crate::Variable<'a>> + '_> {
         Box::new(self.variables().map(|e| e as &dyn crate::Variable<'a>))
     }
+
+    fn capacity(&self, typ: VarType) -> usize {
+        match typ {
+            VarType::Common => self.common.size_bytes(),
+            VarType::System => self.system.size_bytes(),
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn size(&self) -> usize {
+        self.size_bytes() + self.tail.len()
+    }
+
+    fn used_space(&self, typ: VarType) -> usize {
+        match typ {
+            VarType::Common => self.common.used_bytes(),
+            VarType::System => self.system.used_bytes(),
+        }
+    }
+
+    fn duplicates(&self) -> usize {
+        self.common.duplicates + self.system.duplicates
+    }
+
+    fn stray_data(&self) -> Option<usize> {
+        let (first, second) = match self.system_first {
+            true => (&self.system, &self.common),
+            false => (&self.common, &self.system),
+        };
+        let second_start = 32 + first.size_bytes();
+        first
+            .stray
+            .map(|i| 32 + i)
+            .or(second.stray.map(|i| second_start + i))
+            .map(|i| self.offset + i)
+    }
 }
 
 impl Display for Partition<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "size: {}, generation: {}, count: {}",
@@ -347,34 +545,140 @@ impl Display for Partition<'_> {
     }
 }
 
-#[derive(Debug)]
+pub(crate) const BANK_SIZE: usize = 0x10000;
+
+fn adler32(data: &[u8]) -> u32 {
+    adler32::RollingAdler32::from_buffer(data).hash()
+}
+
+// a fresh image with the same variables in both banks
+pub(crate) fn build(
+    common_size: usize,
+    system_size: usize,
+    vars: &[crate::OwnedVariable],
+) -> Result<Vec<u8>> {
+    if 32 + common_size + system_size > BANK_SIZE {
+        return Err(Error::SectionTooBig);
+    }
+    let section = |name, size: usize| Section {
+        header: CHRPHeader {
+            name,
+            size: (size / 16) as u16,
+            signature: 0x70,
+        },
+        values: BTreeMap::new(),
+        order: Vec::new(),
+        duplicates: 0,
+        stray: None,
+    };
+    let mut part = Partition {
+        header: CHRPHeader {
+            name: b"nvram",
+            size: (BANK_SIZE / 16) as u16,
+            signature: 0x7f,
+        },
+        generation: 1,
+        common: section(b"common", common_size),
+        system: section(b"system", system_size),
+        reserved: [0; 8],
+        system_first: false,
+        tail: &[],
+        offset: 0,
+    };
+    for var in vars {
+        crate::Partition::insert_variable(
+            &mut part,
+            &var.name,
+            Cow::Borrowed(&var.value),
+            var.typ,
+        )?;
+    }
+    let mut bank = Vec::with_capacity(BANK_SIZE);
+    part.serialize(&mut bank)?;
+    bank.resize(BANK_SIZE, 0);
+    Ok(bank.repeat(2))
+}
+
+pub fn fix_checksums(nvr: &mut [u8]) -> Vec<crate::ChecksumFix> {
+    let mut ret = Vec::new();
+    for offset in [0, BANK_SIZE] {
+        let Some(bank) = nvr.get_mut(offset..) else {
+            continue;
+        };
+        let Ok(end) = Partition::checksum_end(bank) else {
+            continue;
+        };
+        let old = u32::from_le_bytes(bank[16..20].try_into().unwrap());
+        let new = adler32(&bank[20..end]);
+        if old != new {
+            bank[16..20].copy_from_slice(&new.to_le_bytes());
+            ret.push(crate::ChecksumFix {
+                offset: offset + 16,
+                old,
+                new,
+            });
+        }
+    }
+    ret
+}
+
+#[derive(Debug, Clone)]
 pub struct Nvram<'a> {
     pub partitions: [Partition<'a>; 2],
     pub active: usize,
+    states: [BankState; 2],
+    reason: crate::ActiveReason,
+    // set_generation(), instead of bumping it
+    generation: Option<u32>,
+    // prepare_for_write() copied the live bank into the other one
+    prepared: bool,
 }
 
 impl<'a> Nvram<'a> {
     pub fn parse(nvr: &[u8]) -> Result<Nvram<'_>> {
         let p1;
         let p2;
-        match (Partition::parse(nvr), Partition::parse(&nvr[0x10000..])) {
+        let states;
+        let bank0 = &nvr[..BANK_SIZE.min(nvr.len())];
+        let bank1 = nvr
+            .get(BANK_SIZE..(2 * BANK_SIZE).min(nvr.len()))
+            .unwrap_or(&[]);
+        match (Partition::parse(bank0), Partition::parse(bank1)) {
             (Err(err), Err(_)) => return Err(err),
             (Ok(p1r), Err(_)) => {
+                warn!("v1v2: bank 1 is corrupt, recovering from bank 0");
                 p1 = p1r;
                 p2 = p1.clone();
+                states = [BankState::Valid, BankState::Invalid];
             }
             (Err(_), Ok(p2r)) => {
+                warn!("v1v2: bank 0 is corrupt, recovering from bank 1");
                 p2 = p2r;
                 p1 = p2.clone();
+                states = [BankState::Invalid, BankState::Valid];
             }
             (Ok(p1r), Ok(p2r)) => {
                 p1 = p1r;
                 p2 = p2r;
+                states = [BankState::Valid, BankState::Valid];
             }
         }
-        let active = if p1.generation > p2.generation { 0 } else { 1 };
-        let partitions = [p1, p2];
-        Ok(Nvram { partitions, active })
+        // an invalid bank holds a copy of the valid one, it can't win
+        let generations = [p1.generation, p2.generation];
+        let generations = [0, 1].map(|i| (states[i] == BankState::Valid).then_some(generations[i]));
+        // the second bank is taken on a tie
+        let (active, reason) = crate::select_active(&generations, true).ok_or(Error::ParseError)?;
+        let mut partitions = [p1, p2];
+        partitions[0].offset = 0;
+        partitions[1].offset = BANK_SIZE;
+        Ok(Nvram {
+            partitions,
+            active,
+            states,
+            reason,
+            generation: None,
+            prepared: false,
+        })
     }
 
     pub fn partitions(&self) -> impl Iterator<Item = &Partition<'a>> {
@@ -383,6 +687,14 @@ impl<'a> Nvram<'a> {
 }
 
 impl<'a> crate::Nvram<'a> for Nvram<'a> {
+    fn version(&self) -> crate::Version {
+        crate::Version::V1V2
+    }
+
+    fn boxed_clone(&self) -> Box<dyn crate::Nvram<'a> + 'a> {
+        Box::new(self.clone())
+    }
+
     fn serialize(&self) -> Result<Vec<u8>> {
         let mut v = Vec::with_capacity(self.partitions[0].size_bytes() * 2);
         self.partitions[0].serialize(&mut v)?;
@@ -392,8 +704,12 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
     fn prepare_for_write(&mut self) {
         let inactive = 1 - self.active;
         self.partitions[inactive] = self.partitions[self.active].clone();
-        self.partitions[inactive].generation += 1;
+        self.partitions[inactive].generation = self
+            .generation
+            .unwrap_or(self.partitions[self.active].generation.wrapping_add(1));
+        self.partitions[inactive].offset = inactive * BANK_SIZE;
         self.active = inactive;
+        self.prepared = true;
     }
     // fn active_part(&self) -> &Partition<'a> {
     //     &self.partitions[self.active]
@@ -402,14 +718,304 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
         &mut self.partitions[self.active] as &mut dyn crate::Partition<'a>
     }
 
+    fn set_generation(&mut self, generation: u32) {
+        self.generation = Some(generation);
+        // otherwise the active bank is the live one, prepare_for_write() uses it
+        if self.prepared {
+            self.partitions[self.active].generation = generation;
+        }
+    }
+
+    fn active_index(&self) -> usize {
+        self.active
+    }
+
+    fn bank_states(&self) -> Vec<BankState> {
+        self.states.to_vec()
+    }
+
+    fn active_bank(&self) -> usize {
+        self.active
+    }
+
+    fn active_reason(&self) -> crate::ActiveReason {
+        self.reason
+    }
+
+    fn bank(&self, index: usize) -> Option<&dyn crate::Partition<'a>> {
+        // an invalid bank holds a copy of the valid one, don't show it
+        match self.states.get(index)? {
+            BankState::Valid => Some(&self.partitions[index]),
+            _ => None,
+        }
+    }
+
     fn partitions(&self) -> Box<dyn Iterator<Item = &dyn crate::Partition<'a>> + '_> {
         Box::new(self.partitions().map(|e| e as &dyn crate::Partition<'a>))
     }
 
-    fn apply(&mut self, w: &mut dyn crate::NvramWriter) -> Result<()> {
+    fn partitions_mut(&mut self) -> Box<dyn Iterator<Item = &mut dyn crate::Partition<'a>> + '_> {
+        Box::new(
+            self.partitions
+                .iter_mut()
+                .map(|e| e as &mut dyn crate::Partition<'a>),
+        )
+    }
+
+    fn apply(&mut self, w: &mut dyn crate::NvramWriter) -> Result<usize> {
         let data = self.serialize()?;
         w.erase_if_needed(0, data.len());
-        w.write_all(0, &data).map_err(|e| Error::ApplyError(e))?;
+        w.write_all(0, &data).map_err(Error::ApplyError)?;
+        Ok(data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{vec::VecWriter, Nvram as NvramT};
+    use alloc::vec;
+
+    fn section(name: &'static [u8], size: usize) -> Section<'static> {
+        Section {
+            header: CHRPHeader {
+                name,
+                size: (size / 16) as u16,
+                signature: 0x70,
+            },
+            values: BTreeMap::new(),
+            order: vec![],
+            duplicates: 0,
+            stray: None,
+        }
+    }
+
+    fn empty_nvram() -> Vec<u8> {
+        let part = Partition {
+            header: CHRPHeader {
+                name: b"nvram",
+                size: (BANK_SIZE / 16) as u16,
+                signature: 0x7f,
+            },
+            generation: 1,
+            common: section(b"common", BANK_SIZE - 32 - 0x1000),
+            system: section(b"system", 0x1000),
+            reserved: [0; 8],
+            system_first: false,
+            tail: &[],
+            offset: 0,
+        };
+        let mut data = vec![];
+        part.serialize(&mut data).unwrap();
+        part.serialize(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_write_bumps_generation() -> Result<()> {
+        let data = empty_nvram();
+        let mut nv = Nvram::parse(&data)?;
+        assert_eq!(nv.active_bank(), 1);
+
+        nv.prepare_for_write();
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"test-value"),
+            VarType::Common,
+        )?;
+        let mut w = VecWriter::new(data.clone());
+        assert_eq!(nv.apply(&mut w)?, data.len());
+
+        // the other bank is written with a newer generation and wins
+        let data_after = w.into_bytes();
+        let nv_after = Nvram::parse(&data_after)?;
+        assert_eq!(nv_after.active_bank(), 0);
+        assert_eq!(nv_after.bank(0).unwrap().generation(), 2);
+        assert_eq!(nv_after.bank(1).unwrap().generation(), 1);
+        assert!(nv_after
+            .bank(1)
+            .unwrap()
+            .get_variable(b"test-variable", VarType::Common)
+            .is_none());
+        let test_var = nv_after
+            .bank(0)
+            .unwrap()
+            .get_variable(b"test-variable", VarType::Common)
+            .unwrap();
+        assert_eq!(test_var.value(), Cow::Borrowed(b"test-value"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_offsets() -> Result<()> {
+        let data = empty_nvram();
+        let mut nv = Nvram::parse(&data)?;
+        for (i, part) in NvramT::partitions(&nv).enumerate() {
+            assert_eq!(part.offset(), i * BANK_SIZE);
+            assert_eq!(part.size(), BANK_SIZE);
+        }
+        nv.prepare_for_write();
+        assert_eq!(nv.active_part_mut().offset(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_too_big() -> Result<()> {
+        let data = empty_nvram();
+        let mut nv = Nvram::parse(&data)?;
+        let value = vec![b'a'; 0x1000];
+        // header, "big=" and the terminating NUL come on top of the value
+        assert!(matches!(
+            nv.active_part_mut()
+                .insert_variable(b"big", Cow::Borrowed(&value), VarType::System),
+            Err(Error::VariableTooBig(21))
+        ));
+        assert!(nv
+            .active_part_mut()
+            .get_variable(b"big", VarType::System)
+            .is_none());
+        nv.active_part_mut().insert_variable(
+            b"big",
+            Cow::Borrowed(&value[21..]),
+            VarType::System,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_values() -> Result<()> {
+        use crate::Nvram as _;
+        let val: Vec<u8> = [&b"a\0\0b\xff"[..], &[0; 200], &[0xFF; 130]].concat();
+        let data = empty_nvram();
+        let mut nv = Nvram::parse(&data)?;
+        nv.prepare_for_write();
+        nv.active_part_mut()
+            .insert_variable(b"a", Cow::Borrowed(&val), VarType::Common)?;
+        let data = nv.serialize()?;
+        let mut nv = Nvram::parse(&data)?;
+        let var = nv.active_part_mut().get_variable(b"a", VarType::Common);
+        assert_eq!(var.unwrap().value(), &val[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unescape_empty_runs() {
+        let val = b"a\xff\x00b\xff\x80c\xff\x02";
+        let unescaped: Vec<u8> = UnescapeVal::new(val.iter().copied()).collect();
+        assert_eq!(unescaped, b"abc\0\0");
+    }
+
+    #[test]
+    fn test_partition_eq() -> Result<()> {
+        use crate::Nvram as _;
+        let data = empty_nvram();
+        let mut nv = Nvram::parse(&data)?;
+        nv.prepare_for_write();
+        // same variables, different generation and offset
+        assert!(nv.partitions[0] == nv.partitions[1]);
+        nv.active_part_mut()
+            .insert_variable(b"a", Cow::Borrowed(b"b"), VarType::Common)?;
+        assert!(nv.partitions[0] != nv.partitions[1]);
+        let var = Variable::new(b"a", b"b", VarType::Common);
+        assert!(nv.partitions[nv.active]
+            .common
+            .values
+            .values()
+            .any(|v| *v == var));
+        assert!(var != Variable::new(b"a", b"b", VarType::System));
+        Ok(())
+    }
+
+    #[test]
+    fn test_active_selection() -> Result<()> {
+        use crate::{ActiveReason, Nvram as _};
+        let mut data = empty_nvram();
+        let nv = Nvram::parse(&data)?;
+        assert_eq!(
+            (nv.active_bank(), nv.active_reason()),
+            (1, ActiveReason::Tie)
+        );
+        let mut nv = Nvram::parse(&data)?;
+        nv.prepare_for_write();
+        let newer = nv.serialize()?;
+        let nv = Nvram::parse(&newer)?;
+        assert_eq!(
+            (nv.active_bank(), nv.active_reason()),
+            (0, ActiveReason::Newest)
+        );
+        // with bank 1 corrupt, bank 0 is all that's left
+        data[BANK_SIZE + 40] ^= 0xFF;
+        let nv = Nvram::parse(&data)?;
+        assert_eq!(
+            (nv.active_bank(), nv.active_reason()),
+            (0, ActiveReason::OnlyValid)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_images() {
+        let data = empty_nvram();
+        for len in (0..BANK_SIZE * 2).step_by(251).chain(0..64) {
+            assert!(len >= BANK_SIZE || Nvram::parse(&data[..len]).is_err());
+        }
+        // a section claiming more than the bank holds
+        let mut data = data;
+        data[32 + 2..32 + 4].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(Partition::parse(&data[..BANK_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_regions_survive() -> Result<()> {
+        let extra: Vec<u8> = (0..=255).collect();
+        let part = Partition {
+            header: CHRPHeader {
+                name: b"nvram",
+                size: (BANK_SIZE / 16) as u16,
+                signature: 0x7f,
+            },
+            generation: 1,
+            common: section(b"common", 0x1000),
+            system: section(b"system", BANK_SIZE - 32 - 0x1000 - extra.len()),
+            reserved: *b"reserved",
+            system_first: true,
+            tail: &extra,
+            offset: 0,
+        };
+        let mut data = vec![];
+        part.serialize(&mut data).unwrap();
+        part.serialize(&mut data).unwrap();
+        assert_eq!(data.len(), 2 * BANK_SIZE);
+
+        // untouched, everything comes back byte for byte
+        let mut nv = Nvram::parse(&data)?;
+        assert_eq!(nv.serialize()?, data);
+
+        nv.prepare_for_write();
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"a"),
+            VarType::Common,
+        )?;
+        let mut w = VecWriter::new(data.clone());
+        nv.apply(&mut w)?;
+        let data_after = w.into_bytes();
+        let written = &data_after[..BANK_SIZE];
+        assert_eq!(&written[24..32], b"reserved");
+        assert_eq!(&written[32 + 4..32 + 10], b"system");
+        assert_eq!(&written[BANK_SIZE - extra.len()..], &extra[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicates() -> Result<()> {
+        let mut data = Vec::new();
+        section(b"common", 64).serialize(&mut data)?;
+        data[16..32].copy_from_slice(b"a=1\0b=2\0a=3\0\0\0\0\0");
+        let sec = Section::parse(&data)?;
+        assert_eq!(sec.duplicates, 1);
+        assert_eq!(sec.order, vec![&b"a"[..], b"b"]);
+        assert_eq!(sec.values[&b"a"[..]].value, &b"3"[..]);
         Ok(())
     }
 }
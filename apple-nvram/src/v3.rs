@@ -1,10 +1,11 @@
-use std::{
-    borrow::Cow,
+use alloc::{borrow::Cow, boxed::Box, collections::BTreeSet, vec::Vec};
+use core::{
     fmt::{Display, Formatter},
+    hash::{Hash, Hasher},
     ops::ControlFlow,
 };
 
-use crate::{Error, VarType};
+use crate::{vec::VecWriter, Error, VarType};
 
 // https://github.com/apple-oss-distributions/xnu/blob/main/iokit/Kernel/IONVRAMV3Handler.cpp#L630
 
@@ -12,7 +13,7 @@ const VARIABLE_STORE_SIGNATURE: &[u8; 4] = b"3VVN";
 const VARIABLE_STORE_VERSION: u8 = 0x1;
 const VARIABLE_DATA: u16 = 0x55AA;
 
-const PARTITION_SIZE: usize = 0x10000;
+pub(crate) const PARTITION_SIZE: usize = 0x10000;
 const STORE_HEADER_SIZE: usize = 24;
 const VAR_HEADER_SIZE: usize = 36;
 const VAR_ADDED: u8 = 0x7F;
@@ -26,7 +27,7 @@ const APPLE_SYSTEM_VARIABLE_GUID: &[u8; 16] = &[
     0x40, 0xA0, 0xDD, 0xD2, 0x77, 0xF8, 0x43, 0x92, 0xB4, 0xA3, 0x1E, 0x73, 0x04, 0x20, 0x65, 0x16,
 ];
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 enum Slot<T> {
     Valid(T),
     Invalid,
@@ -60,60 +61,140 @@ impl<T> Slot<T> {
     }
 
     pub fn empty(&self) -> bool {
-        match self {
-            Slot::Empty => true,
-            _ => false,
+        matches!(self, Slot::Empty)
+    }
+}
+
+pub fn is_v3(nvr: &[u8]) -> bool {
+    nvr.chunks(PARTITION_SIZE)
+        .any(|bank| bank.starts_with(VARIABLE_STORE_SIGNATURE))
+}
+
+// a fresh image with the variables in the first bank and the rest empty
+pub(crate) fn build(
+    bank_count: usize,
+    common_size: usize,
+    system_size: usize,
+    vars: &[crate::OwnedVariable],
+) -> crate::Result<Vec<u8>> {
+    let mut part = Partition {
+        header: StoreHeader {
+            name: VARIABLE_STORE_SIGNATURE,
+            size: PARTITION_SIZE as u32,
+            generation: 1,
+            // state and flags as found on existing stores
+            state: 0xFE,
+            flags: 0x5A,
+            version: VARIABLE_STORE_VERSION,
+            system_size: system_size as u32,
+            common_size: common_size as u32,
+        },
+        values: Vec::new(),
+        empty_region_end: PARTITION_SIZE,
+        tail: &[],
+        offset: 0,
+        shadowed: BTreeSet::new(),
+    };
+    for var in vars {
+        crate::Partition::insert_variable(
+            &mut part,
+            &var.name,
+            Cow::Borrowed(&var.value),
+            var.typ,
+        )?;
+    }
+    if common_size + system_size > PARTITION_SIZE || part.total_used() > PARTITION_SIZE {
+        return Err(Error::SectionTooBig);
+    }
+    let mut data = Vec::with_capacity(bank_count * PARTITION_SIZE);
+    part.serialize(&mut data);
+    data.resize(bank_count * PARTITION_SIZE, 0xFF);
+    Ok(data)
+}
+
+pub fn fix_checksums(nvr: &mut [u8]) -> Vec<crate::ChecksumFix> {
+    let mut ret = Vec::new();
+    for (i, bank) in nvr.chunks_exact_mut(PARTITION_SIZE).enumerate() {
+        let Ok(header) = StoreHeader::parse(bank) else {
+            continue;
+        };
+        let size = header.size().min(PARTITION_SIZE);
+        let mut offset = STORE_HEADER_SIZE;
+        // same walk as Partition::parse, minus the crc check
+        while offset + VAR_HEADER_SIZE < size {
+            let Ok((name_size, data_size, old)) = VarHeader::parse(&bank[offset..size])
+                .map(|h| (h.name_size as usize, h.data_size as usize, h.crc))
+            else {
+                break;
+            };
+            let v_begin = offset + VAR_HEADER_SIZE + name_size;
+            let v_end = v_begin + data_size;
+            let new = crc32fast::hash(&bank[v_begin..v_end]);
+            if new != old {
+                bank[offset + 32..offset + 36].copy_from_slice(&new.to_le_bytes());
+                ret.push(crate::ChecksumFix {
+                    offset: i * PARTITION_SIZE + offset + 32,
+                    old,
+                    new,
+                });
+            }
+            offset = v_end;
         }
     }
+    ret
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Nvram<'a> {
+    // kept around to write back the banks that didn't parse
+    raw: &'a [u8],
     partitions: [Slot<Partition<'a>>; 16],
     partition_count: usize,
     active: usize,
+    reason: crate::ActiveReason,
+    // set_generation(), instead of bumping it when moving to the next bank
+    generation: Option<u32>,
 }
 
 impl<'a> Nvram<'a> {
-    pub fn parse(nvr: &'a [u8]) -> crate::Result<Nvram<'_>> {
-        let partition_count = nvr.len() / PARTITION_SIZE;
+    pub fn parse(nvr: &'a [u8]) -> crate::Result<Nvram<'a>> {
+        // anything past the 16th bank is ignored
+        let partition_count = (nvr.len() / PARTITION_SIZE).min(16);
         let mut partitions: [Slot<Partition<'a>>; 16] = Default::default();
-        let mut active = 0;
-        let mut max_gen = 0;
-        let mut valid_partitions = 0;
+        let mut generations = [None; 16];
 
-        for i in 0..partition_count {
+        for (i, slot) in partitions.iter_mut().enumerate().take(partition_count) {
             let offset = i * PARTITION_SIZE;
             if offset >= nvr.len() {
                 break;
             }
             match Partition::parse(&nvr[offset..offset + PARTITION_SIZE]) {
-                Ok(p) => {
-                    let p_gen = p.generation();
-                    if p_gen > max_gen {
-                        active = i;
-                        max_gen = p_gen;
-                    }
-                    partitions[i] = Slot::Valid(p);
-                    valid_partitions += 1;
+                Ok(mut p) => {
+                    p.offset = offset;
+                    generations[i] = Some(p.generation());
+                    *slot = Slot::Valid(p);
                 }
                 Err(V3Error::Empty) => {
-                    partitions[i] = Slot::Empty;
+                    *slot = Slot::Empty;
                 }
                 Err(_) => {
-                    partitions[i] = Slot::Invalid;
+                    debug!("v3: bank {} doesn't parse", i);
+                    *slot = Slot::Invalid;
                 }
             }
         }
 
-        if valid_partitions == 0 {
-            return Err(Error::ParseError);
-        }
+        // the first bank is taken on a tie
+        let (active, reason) = crate::select_active(&generations[..partition_count], false)
+            .ok_or(Error::ParseError)?;
 
         Ok(Nvram {
+            raw: nvr,
             partitions,
             partition_count,
             active,
+            reason,
+            generation: None,
         })
     }
 
@@ -139,12 +220,21 @@ impl<'a> Nvram<'a> {
 }
 
 impl<'a> crate::Nvram<'a> for Nvram<'a> {
+    fn version(&self) -> crate::Version {
+        crate::Version::V3
+    }
+
+    fn boxed_clone(&self) -> Box<dyn crate::Nvram<'a> + 'a> {
+        Box::new(self.clone())
+    }
+
     fn serialize(&self) -> crate::Result<Vec<u8>> {
-        let mut v = Vec::with_capacity(self.partition_count * PARTITION_SIZE);
-        for p in self.partitions() {
-            p.serialize(&mut v);
-        }
-        Ok(v)
+        // the image as apply() leaves it, which may have rolled over into
+        // the next bank
+        let mut nv = self.clone();
+        let mut w = VecWriter::new(self.raw[..self.partition_count * PARTITION_SIZE].to_vec());
+        nv.apply(&mut w)?;
+        Ok(w.into_bytes())
     }
 
     fn prepare_for_write(&mut self) {
@@ -155,11 +245,63 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
         Box::new(self.partitions().map(|p| p as &dyn crate::Partition<'a>))
     }
 
+    fn partitions_mut(&mut self) -> Box<dyn Iterator<Item = &mut dyn crate::Partition<'a>> + '_> {
+        Box::new(
+            self.partitions
+                .iter_mut()
+                .take(self.partition_count)
+                .filter_map(|x| match x {
+                    Slot::Valid(p) => Some(p as &mut dyn crate::Partition<'a>),
+                    Slot::Invalid => None,
+                    Slot::Empty => None,
+                }),
+        )
+    }
+
     fn active_part_mut(&mut self) -> &mut dyn crate::Partition<'a> {
         self.partitions[self.active].as_mut().unwrap()
     }
 
-    fn apply(&mut self, w: &mut dyn crate::NvramWriter) -> crate::Result<()> {
+    fn set_generation(&mut self, generation: u32) {
+        self.generation = Some(generation);
+    }
+
+    fn bank_states(&self) -> Vec<crate::BankState> {
+        self.partitions
+            .iter()
+            .take(self.partition_count)
+            .map(|p| match p {
+                Slot::Valid(_) => crate::BankState::Valid,
+                Slot::Invalid => crate::BankState::Invalid,
+                Slot::Empty => crate::BankState::Empty,
+            })
+            .collect()
+    }
+
+    fn active_bank(&self) -> usize {
+        self.active
+    }
+
+    fn active_reason(&self) -> crate::ActiveReason {
+        self.reason
+    }
+
+    fn bank(&self, index: usize) -> Option<&dyn crate::Partition<'a>> {
+        match self.partitions[..self.partition_count].get(index)? {
+            Slot::Valid(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    fn active_index(&self) -> usize {
+        // partitions() skips over empty and invalid slots
+        self.partitions[..self.active]
+            .iter()
+            .filter(|p| matches!(p, Slot::Valid(_)))
+            .count()
+    }
+
+    fn apply(&mut self, w: &mut dyn crate::NvramWriter) -> crate::Result<usize> {
         let ap = self.active_part();
         let offset;
         // there aren't really any sections in v3 but the store header still
@@ -171,8 +313,10 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
             return Err(Error::SectionTooBig);
         }
 
-        // if total size is too big, copy added variables to the next bank
-        if ap.total_used() <= ap.usable_size() {
+        // if total size is too big, copy added variables to the next bank.
+        // A new generation goes there too, flash can't take a changed header
+        // in place without an erase
+        if ap.total_used() <= ap.usable_size() && self.generation.is_none() {
             offset = (self.active * PARTITION_SIZE) as u32;
         } else {
             let new_active = (self.active + 1) % self.partition_count;
@@ -181,24 +325,26 @@ impl<'a> crate::Nvram<'a> for Nvram<'a> {
                 w.erase_if_needed(offset, PARTITION_SIZE);
             }
             // must only clone 0x7F variables to the next partition
-            self.partitions[new_active] = Slot::Valid(
-                self.partitions[self.active]
-                    .as_ref()
-                    .unwrap()
-                    .clone_active(),
-            );
+            let mut next = self.partitions[self.active]
+                .as_ref()
+                .unwrap()
+                .clone_active(offset as usize);
+            // used up, applying again moves on by one as usual
+            if let Some(generation) = self.generation.take() {
+                next.header.generation = generation;
+            }
+            self.partitions[new_active] = Slot::Valid(next);
             self.active = new_active;
             // we could still have too many active variables
-            if self.active_part().total_used() > PARTITION_SIZE {
+            if self.active_part().total_used() > self.active_part().header.size() {
                 return Err(Error::SectionTooBig);
             }
         }
 
         let mut data = Vec::with_capacity(PARTITION_SIZE);
         self.active_part().serialize(&mut data);
-        w.write_all(offset, &data)
-            .map_err(|e| Error::ApplyError(e))?;
-        Ok(())
+        w.write_all(offset, &data).map_err(Error::ApplyError)?;
+        Ok(data.len())
     }
 }
 
@@ -207,6 +353,12 @@ pub struct Partition<'a> {
     pub header: StoreHeader<'a>,
     pub values: Vec<Variable<'a>>,
     empty_region_end: usize,
+    // whatever follows the empty region, written back as is
+    tail: &'a [u8],
+    offset: usize,
+    // indices into values of live entries a later one with the same name and
+    // guid overrides, left as they are on disk
+    shadowed: BTreeSet<usize>,
 }
 
 #[derive(Debug)]
@@ -215,11 +367,15 @@ enum V3Error {
     Empty,
 }
 
-type Result<T> = std::result::Result<T, V3Error>;
+type Result<T> = core::result::Result<T, V3Error>;
 
 impl<'a> Partition<'a> {
     fn parse(nvr: &'a [u8]) -> Result<Partition<'a>> {
-        if let Ok(header) = StoreHeader::parse(&nvr[..STORE_HEADER_SIZE]) {
+        if let Ok(header) = StoreHeader::parse(nvr) {
+            if header.size() < STORE_HEADER_SIZE || header.size() > nvr.len() {
+                warn!("v3: store size {:#x} doesn't fit the bank", header.size());
+                return Err(V3Error::ParseError);
+            }
             let mut offset = STORE_HEADER_SIZE;
             let mut values = Vec::new();
             // one byte past the last 0xFF or the end of partition
@@ -247,9 +403,10 @@ impl<'a> Partition<'a> {
                     break;
                 }
 
-                let Ok(v_header) = VarHeader::parse(&nvr[offset..]) else {
+                let Ok(v_header) = VarHeader::parse(&nvr[offset..header.size()]) else {
                     // if there's no valid header, just end here and return values parsed so far
                     // we also know there is no space for adding any new or updated variables
+                    debug!("v3: no variable header at {:#x}, stopping there", offset);
                     empty_region_end = offset;
                     break;
                 };
@@ -264,6 +421,10 @@ impl<'a> Partition<'a> {
 
                 let crc = crc32fast::hash(value);
                 if crc != v_header.crc {
+                    warn!(
+                        "v3: crc mismatch in the variable at {:#x}, stored {:08x}, computed {:08x}",
+                        offset, v_header.crc, crc
+                    );
                     return Err(V3Error::ParseError);
                 }
                 let v = Variable {
@@ -276,10 +437,23 @@ impl<'a> Partition<'a> {
                 values.push(v);
             }
 
+            // the last entry wins, earlier live copies are skipped over
+            let mut seen = BTreeSet::new();
+            let mut shadowed = BTreeSet::new();
+            for (i, v) in values.iter().enumerate().rev() {
+                if v.header.state == VAR_ADDED && !seen.insert((v.key, v.header.guid)) {
+                    debug!("v3: duplicate variable, the last one wins");
+                    shadowed.insert(i);
+                }
+            }
+
             Ok(Partition {
+                tail: &nvr[empty_region_end.min(header.size())..header.size()],
                 header,
                 values,
                 empty_region_end,
+                offset: 0,
+                shadowed,
             })
         } else {
             match nvr.iter().copied().try_for_each(|v| match v {
@@ -318,17 +492,15 @@ impl<'a> Partition<'a> {
 
     // size of active system variables
     fn system_used(&self) -> usize {
-        self.values
-            .iter()
-            .filter(|&v| v.header.state == VAR_ADDED && v.header.guid == APPLE_SYSTEM_VARIABLE_GUID)
+        self.variables()
+            .filter(|&v| v.header.guid == APPLE_SYSTEM_VARIABLE_GUID)
             .fold(0, |acc, v| acc + v.size())
     }
 
     // size of active common variables
     fn common_used(&self) -> usize {
-        self.values
-            .iter()
-            .filter(|&v| v.header.state == VAR_ADDED && v.header.guid == APPLE_COMMON_VARIABLE_GUID)
+        self.variables()
+            .filter(|&v| v.header.guid == APPLE_COMMON_VARIABLE_GUID)
             .fold(0, |acc, v| acc + v.size())
     }
 
@@ -354,43 +526,59 @@ impl<'a> Partition<'a> {
             var.serialize(v);
         }
         let my_size = v.len() - start_size;
-        debug_assert!(v.len() == self.total_used());
+        debug_assert!(my_size == self.total_used());
 
-        // padding
-        for _ in 0..(self.header.size() - my_size) {
+        // padding, up to whatever was found after the empty space unless
+        // the variables have grown into it
+        let tail = if my_size + self.tail.len() <= self.header.size() {
+            self.tail
+        } else {
+            &[]
+        };
+        for _ in 0..(self.header.size() - my_size - tail.len()) {
             v.push(0xFF);
         }
+        v.extend_from_slice(tail);
     }
 
     fn variables(&self) -> impl Iterator<Item = &Variable<'a>> {
-        self.values.iter().filter(|v| v.header.state == VAR_ADDED)
+        self.values
+            .iter()
+            .enumerate()
+            .filter(|(i, v)| v.header.state == VAR_ADDED && !self.shadowed.contains(i))
+            .map(|(_, v)| v)
     }
 
-    fn clone_active(&self) -> Partition<'a> {
+    fn clone_active(&self, offset: usize) -> Partition<'a> {
         let mut header = self.header.clone();
-        header.generation += 1;
+        header.generation = header.generation.wrapping_add(1);
         Partition {
             header,
-            values: self
-                .values
-                .iter()
-                .filter_map(|v| {
-                    if v.header.state == VAR_ADDED {
-                        Some(v.clone())
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
+            values: self.variables().cloned().collect(),
             empty_region_end: self.header.size(),
+            tail: &[],
+            offset,
+            shadowed: BTreeSet::new(),
         }
     }
 }
 
+impl PartialEq for Partition<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        crate::same_variables(self, other)
+    }
+}
+
+impl Eq for Partition<'_> {}
+
 impl<'a> crate::Partition<'a> for Partition<'a> {
+    fn generation(&self) -> u32 {
+        self.generation()
+    }
+
     fn get_variable(&self, key: &[u8], typ: VarType) -> Option<&dyn crate::Variable<'a>> {
-        self.values.iter().find_map(|e| {
-            if e.key == key && e.typ() == typ && e.header.state == VAR_ADDED {
+        self.variables().find_map(|e| {
+            if e.key == key && e.typ() == typ {
                 Some(e as &dyn crate::Variable<'a>)
             } else {
                 None
@@ -398,8 +586,24 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
         })
     }
 
-    fn insert_variable(&mut self, key: &'a [u8], value: Cow<'a, [u8]>, typ: VarType) {
-        // invalidate any previous variable instances
+    fn insert_variable(
+        &mut self,
+        key: &'a [u8],
+        value: Cow<'a, [u8]>,
+        typ: VarType,
+    ) -> crate::Result<()> {
+        // the old instance stops counting once it's replaced
+        let old: usize = self
+            .variables()
+            .filter(|v| v.key == key && v.typ() == typ)
+            .map(|v| v.size())
+            .sum();
+        let used = self.used_space(typ) - old + VAR_HEADER_SIZE + key.len() + 1 + value.len();
+        if used > self.capacity(typ) {
+            return Err(Error::VariableTooBig(used - self.capacity(typ)));
+        }
+
+        // invalidate any previous variable instances, shadowed ones included
         for var in self.entries_added(key, typ) {
             var.header.state = var.header.state & VAR_DELETED & VAR_IN_DELETED_TRANSITION;
         }
@@ -421,6 +625,7 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
             value,
         };
         self.values.push(var);
+        Ok(())
     }
 
     fn remove_variable(&mut self, key: &'a [u8], typ: VarType) {
@@ -430,13 +635,57 @@ impl<'a> crate::Partition<'a> for Partition<'a> {
         }
     }
 
+    fn clear(&mut self, typ: VarType) {
+        // entries can only be marked deleted in place, the space they take up
+        // is reclaimed once the variables get copied to the next bank
+        for var in self
+            .values
+            .iter_mut()
+            .filter(|v| v.typ() == typ && v.header.state == VAR_ADDED)
+        {
+            var.header.state = var.header.state & VAR_DELETED & VAR_IN_DELETED_TRANSITION;
+        }
+    }
+
     fn variables(&self) -> Box<dyn Iterator<Item = &dyn crate::Variable<'a>> + '_> {
         Box::new(self.variables().map(|e| e as &dyn crate::Variable<'a>))
     }
+
+    fn capacity(&self, typ: VarType) -> usize {
+        match typ {
+            VarType::Common => self.common_size(),
+            VarType::System => self.system_size(),
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn size(&self) -> usize {
+        self.header.size()
+    }
+
+    fn used_space(&self, typ: VarType) -> usize {
+        match typ {
+            VarType::Common => self.common_used(),
+            VarType::System => self.system_used(),
+        }
+    }
+
+    fn duplicates(&self) -> usize {
+        self.shadowed.len()
+    }
+
+    // the tail is whatever follows the erased space, or if the variables
+    // stopped at something that isn't one, everything from there on
+    fn stray_data(&self) -> Option<usize> {
+        (!self.tail.is_empty()).then(|| self.offset + self.header.size() - self.tail.len())
+    }
 }
 
 impl Display for Partition<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "size: {}, total_used: {}, system_used: {}, common_used: {}, generation: 0x{:02x}, state: 0x{:02x}, flags: 0x{:02x}, count: {}",
@@ -466,6 +715,9 @@ pub struct StoreHeader<'a> {
 
 impl<'a> StoreHeader<'a> {
     fn parse(nvr: &[u8]) -> Result<StoreHeader<'_>> {
+        if nvr.len() < STORE_HEADER_SIZE {
+            return Err(V3Error::ParseError);
+        }
         let name = &nvr[..4];
         let size = u32::from_le_bytes(nvr[4..8].try_into().unwrap());
         let generation = u32::from_le_bytes(nvr[8..12].try_into().unwrap());
@@ -538,25 +790,44 @@ impl<'a> Variable<'a> {
     }
 }
 
+impl PartialEq for Variable<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        crate::variable_key(self) == crate::variable_key(other)
+    }
+}
+
+impl Eq for Variable<'_> {}
+
+impl Hash for Variable<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        crate::variable_key(self).hash(state)
+    }
+}
+
 impl<'a> crate::Variable<'a> for Variable<'a> {
+    fn name(&self) -> &'a [u8] {
+        self.key
+    }
+
+    fn typ(&self) -> VarType {
+        Variable::typ(self)
+    }
+
     fn value(&self) -> Cow<'a, [u8]> {
         self.value.clone()
     }
 }
 
 impl Display for Variable<'_> {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        let key = String::from_utf8_lossy(self.key);
-        let mut value = String::new();
-        for c in self.value.iter().copied() {
-            if (c as char).is_ascii() && !(c as char).is_ascii_control() {
-                value.push(c as char);
-            } else {
-                value.push_str(&format!("%{c:02x}"));
-            }
-        }
-
-        write!(f, "{}:{}={}", self.typ(), key, value)
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        let key = crate::escape(self.key, b"=");
+        write!(
+            f,
+            "{}:{}={}",
+            self.typ(),
+            key,
+            crate::escape(&self.value, b"")
+        )
     }
 }
 
@@ -572,6 +843,9 @@ pub struct VarHeader<'a> {
 
 impl<'a> VarHeader<'a> {
     fn parse(nvr: &[u8]) -> Result<VarHeader<'_>> {
+        if nvr.len() < VAR_HEADER_SIZE {
+            return Err(V3Error::ParseError);
+        }
         let start_id = u16::from_le_bytes(nvr[..2].try_into().unwrap());
         if start_id != VARIABLE_DATA {
             return Err(V3Error::ParseError);
@@ -583,7 +857,9 @@ impl<'a> VarHeader<'a> {
         let guid = &nvr[16..32];
         let crc = u32::from_le_bytes(nvr[32..36].try_into().unwrap());
 
-        if VAR_HEADER_SIZE + (name_size + data_size) as usize > nvr.len() {
+        // names are NUL-terminated, so an empty one can't be valid;
+        // sizes are added as usize so huge values can't wrap around
+        if name_size == 0 || VAR_HEADER_SIZE + name_size as usize + data_size as usize > nvr.len() {
             return Err(V3Error::ParseError);
         }
 
@@ -613,6 +889,7 @@ impl<'a> VarHeader<'a> {
 mod tests {
     use super::*;
     use crate::{Nvram as NvramT, NvramWriter, Partition};
+    use alloc::{borrow::ToOwned, vec};
 
     struct TestNvram {
         data: Vec<u8>,
@@ -640,7 +917,11 @@ mod tests {
             self.erase_count += 1;
         }
 
-        fn write_all(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()> {
+        fn write_all(
+            &mut self,
+            offset: u32,
+            buf: &[u8],
+        ) -> core::result::Result<(), crate::WriteError> {
             for (d, s) in self
                 .data
                 .iter_mut()
@@ -669,6 +950,19 @@ mod tests {
         data
     }
 
+    #[test]
+    fn test_tie_picks_first_bank() -> crate::Result<()> {
+        use crate::{ActiveReason, Nvram as _};
+        let mut data = empty_nvram(3);
+        data.copy_within(0..PARTITION_SIZE, 2 * PARTITION_SIZE);
+        let nv = Nvram::parse(&data)?;
+        assert_eq!(
+            (nv.active_bank(), nv.active_reason()),
+            (0, ActiveReason::Tie)
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_insert_variable() -> crate::Result<()> {
         let mut nvr = TestNvram::new(empty_nvram(2));
@@ -682,7 +976,7 @@ mod tests {
             b"test-variable",
             Cow::Borrowed(b"test-value"),
             VarType::Common,
-        );
+        )?;
 
         // write changes
         nv.apply(&mut nvr)?;
@@ -712,7 +1006,7 @@ mod tests {
             b"test-variable",
             Cow::Borrowed(b"test-value2"),
             VarType::Common,
-        );
+        )?;
 
         // write changes
         nv_after.apply(&mut nvr)?;
@@ -761,14 +1055,14 @@ mod tests {
             b"test-large-variable",
             Cow::Borrowed(&orig_sys_val),
             VarType::System,
-        );
+        )?;
 
         let orig_common_val = vec![b'.'; 24576];
         nv.active_part_mut().insert_variable(
             b"test-large-variable",
             Cow::Borrowed(&orig_common_val),
             VarType::Common,
-        );
+        )?;
 
         // write changes
         nv.apply(&mut nvr)?;
@@ -789,14 +1083,14 @@ mod tests {
             b"test-large-variable",
             Cow::Borrowed(&updated_sys_val),
             VarType::System,
-        );
+        )?;
 
         let updated_common_val = vec![b'.'; 25000];
         nv_after.active_part_mut().insert_variable(
             b"test-large-variable",
             Cow::Borrowed(&updated_common_val),
             VarType::Common,
-        );
+        )?;
 
         assert_eq!(nv_after.active_part().values.len(), 4);
 
@@ -863,7 +1157,7 @@ mod tests {
             b"test-variable",
             Cow::Borrowed(b"test-value"),
             VarType::Common,
-        );
+        )?;
 
         // write changes
         nv.apply(&mut nvr)?;
@@ -894,7 +1188,7 @@ mod tests {
             b"test-variable",
             Cow::Borrowed(b"test-value2"),
             VarType::Common,
-        );
+        )?;
 
         // write changes
         nv_after.apply(&mut nvr)?;
@@ -903,4 +1197,259 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_clear() -> crate::Result<()> {
+        let mut nvr = TestNvram::new(empty_nvram(2));
+        let data = nvr.get_data().to_owned();
+        let mut nv = Nvram::parse(&data)?;
+
+        let part = nv.active_part_mut();
+        part.insert_variable(b"common-var", Cow::Borrowed(b"a"), VarType::Common)?;
+        part.insert_variable(b"common-var2", Cow::Borrowed(b"b"), VarType::Common)?;
+        part.insert_variable(b"system-var", Cow::Borrowed(b"c"), VarType::System)?;
+        part.clear(VarType::Common);
+        assert_eq!(part.common_used(), 0);
+        nv.apply(&mut nvr)?;
+
+        let data_after = nvr.get_data().to_owned();
+        let nv_after = Nvram::parse(&data_after)?;
+        let names: Vec<_> = nv_after.active_part().variables().map(|v| v.key).collect();
+        assert_eq!(names, [b"system-var"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vec_writer_round_trip() -> crate::Result<()> {
+        let data = empty_nvram(2);
+        let mut nv = Nvram::parse(&data)?;
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"test-value"),
+            VarType::Common,
+        )?;
+        let mut w = crate::vec::VecWriter::new(data.clone());
+        // only the active bank is written
+        assert_eq!(nv.apply(&mut w)?, PARTITION_SIZE);
+
+        let data_after = w.into_bytes();
+        assert_eq!(data_after.len(), data.len());
+        let nv_after = Nvram::parse(&data_after)?;
+        let test_var = nv_after
+            .active_part()
+            .get_variable(b"test-variable", VarType::Common)
+            .unwrap();
+        assert_eq!(test_var.value(), Cow::Borrowed(b"test-value"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_values_borrow_from_image() -> crate::Result<()> {
+        let mut nvr = TestNvram::new(empty_nvram(2));
+        let data = nvr.get_data().to_owned();
+        let mut nv = Nvram::parse(&data)?;
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Owned(b"test-value".to_vec()),
+            VarType::Common,
+        )?;
+        nv.apply(&mut nvr)?;
+
+        let data_after = nvr.get_data().to_owned();
+        let nv_after = Nvram::parse(&data_after)?;
+        let var = nv_after.bank(0).unwrap().variables().next().unwrap();
+        assert!(matches!(var.value(), Cow::Borrowed(_)));
+        let owned = var.to_owned_variable();
+        drop(nv_after);
+        drop(data_after);
+        assert_eq!(owned.name, b"test-variable");
+        assert_eq!(owned.value, b"test-value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_round_trip() -> crate::Result<()> {
+        let value: Vec<u8> = (0..=255).collect();
+        let data = empty_nvram(2);
+        let mut nv = Nvram::parse(&data)?;
+        nv.active_part_mut().insert_variable(
+            b"a=b%c\xff",
+            Cow::Borrowed(&value),
+            VarType::Common,
+        )?;
+        let data = nv.serialize()?;
+        let nv = Nvram::parse(&data)?;
+        let var = nv.active_part().variables().next().unwrap();
+        let shown = alloc::format!("{}", var);
+        let (name, shown_value) = shown["common:".len()..].split_once('=').unwrap();
+        assert_eq!(crate::unescape_value(name)?, b"a=b%c\xff");
+        assert_eq!(crate::unescape_value(shown_value)?, value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_offsets() -> crate::Result<()> {
+        let mut data = empty_nvram(3);
+        data[2 * PARTITION_SIZE..2 * PARTITION_SIZE + STORE_HEADER_SIZE]
+            .copy_from_slice(store_header());
+        let nv = Nvram::parse(&data)?;
+        let serialized = nv.serialize()?;
+        let offsets: Vec<_> = crate::Nvram::partitions(&nv).map(|p| p.offset()).collect();
+        assert_eq!(offsets, [0, 2 * PARTITION_SIZE]);
+        for part in crate::Nvram::partitions(&nv) {
+            let bank = &serialized[part.offset()..part.offset() + part.size()];
+            assert!(bank.starts_with(VARIABLE_STORE_SIGNATURE));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_sizes() -> crate::Result<()> {
+        let data = empty_nvram(2);
+        let mut nv = Nvram::parse(&data)?;
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"test-value"),
+            VarType::Common,
+        )?;
+        let data = nv.serialize()?;
+        assert!(Nvram::parse(&data)?
+            .active_part()
+            .get_variable(b"test-variable", VarType::Common)
+            .is_some());
+        assert!(Nvram::parse(&data[..PARTITION_SIZE - 1]).is_err());
+
+        let var = STORE_HEADER_SIZE;
+        for (offset, value) in [
+            (4, u32::MAX),
+            (4, 1),
+            (var + 8, 0),
+            (var + 8, u32::MAX),
+            (var + 12, u32::MAX),
+        ] {
+            let mut bad = data.clone();
+            bad[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            // either the bank is rejected or the variable is dropped
+            if let Ok(nv) = Nvram::parse(&bad) {
+                assert!(nv
+                    .active_part()
+                    .get_variable(b"test-variable", VarType::Common)
+                    .is_none());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_regions_survive() -> crate::Result<()> {
+        let mut data = empty_nvram(3);
+        // junk past the empty space, and a bank that doesn't parse
+        data[0x8000..0x8004].copy_from_slice(b"junk");
+        data[2 * PARTITION_SIZE] = 0;
+        let mut nv = Nvram::parse(&data)?;
+        assert_eq!(nv.serialize()?, data);
+
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"test-value"),
+            VarType::Common,
+        )?;
+        let mut w = crate::vec::VecWriter::new(data.clone());
+        nv.apply(&mut w)?;
+        let data_after = w.into_bytes();
+        assert_eq!(&data_after[0x8000..0x8004], b"junk");
+        assert_eq!(nv.serialize()?, data_after);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicates() -> crate::Result<()> {
+        let data = empty_nvram(2);
+        let mut nv = Nvram::parse(&data)?;
+        let part = nv.active_part_mut();
+        part.insert_variable(b"a", Cow::Borrowed(b"1"), VarType::Common)?;
+        part.insert_variable(b"a", Cow::Borrowed(b"2"), VarType::Common)?;
+        // as if the old entry had never been marked deleted
+        part.values[0].header.state = VAR_ADDED;
+        let data = nv.serialize()?;
+
+        let mut nv = Nvram::parse(&data)?;
+        assert_eq!(nv.active_part().duplicates(), 1);
+        let vars: Vec<_> = nv.active_part().variables().collect();
+        assert_eq!(vars.len(), 1);
+        assert_eq!(vars[0].value, Cow::Borrowed(b"2"));
+        // untouched, it's written back as it was
+        assert_eq!(nv.serialize()?, data);
+
+        // replacing it deletes the shadowed copy too
+        nv.active_part_mut()
+            .insert_variable(b"a", Cow::Borrowed(b"3"), VarType::Common)?;
+        let data = nv.serialize()?;
+        let nv = Nvram::parse(&data)?;
+        assert_eq!(nv.active_part().duplicates(), 0);
+        let vars: Vec<_> = nv.active_part().variables().collect();
+        assert_eq!(vars.len(), 1);
+        assert_eq!(vars[0].value, Cow::Borrowed(b"3"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_full_bank() -> crate::Result<()> {
+        let data = empty_nvram(2);
+        let mut nv = Nvram::parse(&data)?;
+        // every replacement leaves the old entry behind, deleted, until the
+        // bank has no room left
+        for i in 0..80u8 {
+            nv.active_part_mut().insert_variable(
+                b"test-variable",
+                Cow::Owned(alloc::vec![i; 1000]),
+                VarType::Common,
+            )?;
+            nv.serialize()?;
+        }
+        assert!(nv.active_part().total_used() > PARTITION_SIZE);
+
+        let serialized = nv.serialize()?;
+        let mut w = VecWriter::new(data.clone());
+        nv.apply(&mut w)?;
+        assert_eq!(serialized, w.bytes());
+        let nv = Nvram::parse(&serialized)?;
+        assert_eq!(nv.active, 1);
+        let var = nv
+            .active_part()
+            .get_variable(b"test-variable", VarType::Common)
+            .unwrap();
+        assert_eq!(var.value(), alloc::vec![79; 1000]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_generation_moves_bank() -> crate::Result<()> {
+        use crate::Nvram as _;
+        let mut nvr = TestNvram::new(empty_nvram(2));
+        let data = nvr.get_data().to_owned();
+        let mut nv = Nvram::parse(&data)?;
+        nv.set_generation(100);
+        nv.active_part_mut().insert_variable(
+            b"test-variable",
+            Cow::Borrowed(b"test-value"),
+            VarType::Common,
+        )?;
+        nv.apply(&mut nvr)?;
+        assert_eq!(nv.active, 1);
+
+        // the bank that was live keeps its header bytes as they were
+        let data_after = nvr.get_data().to_owned();
+        assert_eq!(data_after[..STORE_HEADER_SIZE], data[..STORE_HEADER_SIZE]);
+        let nv_after = Nvram::parse(&data_after)?;
+        assert_eq!(nv_after.active, 1);
+        assert_eq!(nv_after.generation(), 100);
+        assert!(nv_after
+            .active_part()
+            .get_variable(b"test-variable", VarType::Common)
+            .is_some());
+        assert_eq!(nv.serialize()?, data_after);
+        Ok(())
+    }
 }
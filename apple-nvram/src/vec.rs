@@ -0,0 +1,81 @@
+use alloc::vec::Vec;
+
+use crate::{NvramWriter, WriteError};
+
+// keeps the image in memory, start it from the parsed image since v3 only
+// writes the bank that changed
+#[derive(Debug, Default)]
+pub struct VecWriter {
+    data: Vec<u8>,
+}
+
+impl VecWriter {
+    pub fn new(data: Vec<u8>) -> VecWriter {
+        VecWriter { data }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    // sends only the blocks that differ from `old` on to w, erasing each one
+    // first, and returns how many bytes that came to
+    pub fn write_changes(
+        &self,
+        old: &[u8],
+        block_size: usize,
+        w: &mut dyn NvramWriter,
+    ) -> Result<usize, WriteError> {
+        let mut written = 0;
+        for (i, block) in self.data.chunks(block_size).enumerate() {
+            let offset = i * block_size;
+            if old.get(offset..offset + block.len()) == Some(block) {
+                continue;
+            }
+            w.erase_if_needed(offset as u32, block.len());
+            w.write_all(offset as u32, block)?;
+            written += block.len();
+        }
+        Ok(written)
+    }
+}
+
+impl NvramWriter for VecWriter {
+    fn erase_if_needed(&mut self, offset: u32, size: usize) {
+        for b in self.data.iter_mut().skip(offset as usize).take(size) {
+            *b = 0xFF;
+        }
+    }
+
+    fn write_all(&mut self, offset: u32, buf: &[u8]) -> Result<(), WriteError> {
+        let offset = offset as usize;
+        if self.data.len() < offset + buf.len() {
+            self.data.resize(offset + buf.len(), 0xFF);
+        }
+        self.data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_write_changes() {
+        let old = vec![0; 0x3000];
+        let mut new = VecWriter::new(old.clone());
+        new.write_all(0x1800, b"changed").unwrap();
+        let mut device = VecWriter::new(old.clone());
+        assert_eq!(
+            new.write_changes(&old, 0x1000, &mut device).unwrap(),
+            0x1000
+        );
+        assert_eq!(device.bytes(), new.bytes());
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    fmt::{Display, Formatter},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use crate::{first_diff, NvramWriter};
+
+// what VerifyingWriter fails with, wrapped in an io::Error of kind
+// InvalidData, `offset` is the first byte that didn't read back
+#[derive(Debug)]
+pub struct VerifyError {
+    pub offset: u64,
+    pub written: u8,
+    pub read: u8,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "reading back offset {:#x} gave {:02x} instead of {:02x}",
+            self.offset, self.read, self.written
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// passes everything on to another writer, then reads each write back from
+// `file`, which should be the same device or image, so flash that silently
+// didn't take the write is caught right away
+pub struct VerifyingWriter<W> {
+    inner: W,
+    file: File,
+}
+
+impl<W: NvramWriter> VerifyingWriter<W> {
+    pub fn new(inner: W, file: File) -> VerifyingWriter<W> {
+        VerifyingWriter { inner, file }
+    }
+}
+
+impl<W: NvramWriter> NvramWriter for VerifyingWriter<W> {
+    fn erase_if_needed(&mut self, offset: u32, size: usize) {
+        self.inner.erase_if_needed(offset, size);
+    }
+
+    fn write_all(&mut self, offset: u32, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(offset, buf)?;
+        let mut read = vec![0; buf.len()];
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.read_exact(&mut read)?;
+        match first_diff(buf, &read) {
+            None => Ok(()),
+            Some((i, written, read)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                VerifyError {
+                    offset: offset as u64 + i as u64,
+                    written,
+                    read,
+                },
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::FileWriter;
+
+    #[test]
+    fn test_verifying_writer() -> io::Result<()> {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("apple-nvram-verify-{}", std::process::id()));
+        let other = dir.join(format!("apple-nvram-verify-other-{}", std::process::id()));
+        std::fs::write(&path, [0; 64])?;
+        std::fs::write(&other, [0; 64])?;
+        let open = |p| File::options().read(true).write(true).open(p);
+
+        let mut w = VerifyingWriter::new(FileWriter::new(open(&path)?), open(&path)?);
+        w.write_all(8, b"abc")?;
+        // writes that never make it to the file being read back
+        let mut w = VerifyingWriter::new(FileWriter::new(open(&path)?), open(&other)?);
+        let e = w.write_all(16, b"\0\0x").unwrap_err();
+        let e = e.get_ref().unwrap().downcast_ref::<VerifyError>().unwrap();
+        assert_eq!((e.offset, e.written, e.read), (18, b'x', 0));
+
+        std::fs::remove_file(path)?;
+        std::fs::remove_file(other)
+    }
+}
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MIT
+// Every image in tests/fixtures has to come back out of the serializer byte
+// for byte, and keep its variables through a write.
+use std::{borrow::Cow, fs, path::PathBuf};
+
+use apple_nvram::{first_diff, nvram_parse, VarType, Version};
+
+fn fixture_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+fn fixtures() -> Vec<(String, Vec<u8>)> {
+    let mut images: Vec<_> = fs::read_dir(fixture_dir())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "bin"))
+        .map(|p| {
+            let name = p.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(&p).unwrap())
+        })
+        .collect();
+    images.sort();
+    assert!(!images.is_empty());
+    images
+}
+
+// a bare assert_eq on two whole images isn't much help in finding out why
+fn assert_same_image(name: &str, got: &[u8], expected: &[u8]) {
+    if let Some((i, got, expected)) = first_diff(got, expected) {
+        panic!(
+            "{}: first difference at {:#x}: {:02x} instead of {:02x}",
+            name, i, got, expected
+        );
+    }
+    assert_eq!(got.len(), expected.len(), "{}: size differs", name);
+}
+
+fn sorted_vars(nv: &mut dyn apple_nvram::Nvram) -> Vec<(VarType, Vec<u8>, Vec<u8>)> {
+    let mut vars: Vec<_> = nv
+        .active_part_mut()
+        .variables()
+        .map(|v| (v.typ(), v.name().to_vec(), v.value().into_owned()))
+        .collect();
+    vars.sort();
+    vars
+}
+
+#[test]
+fn test_untouched_images_round_trip() {
+    for (name, data) in fixtures() {
+        let nv = nvram_parse(&data).unwrap();
+        assert_same_image(&name, &nv.serialize().unwrap(), &data);
+    }
+}
+
+#[test]
+fn test_write_keeps_variables() {
+    for (name, data) in fixtures() {
+        let mut nv = nvram_parse(&data).unwrap();
+        // v1v2 always moves on to the other bank, v3 appends in place while
+        // there's room
+        let generation = nv.active_part_mut().generation()
+            + match nv.version() {
+                Version::V1V2 => 1,
+                Version::V3 => 0,
+            };
+        let mut expected = sorted_vars(&mut *nv);
+        nv.prepare_for_write();
+        nv.active_part_mut()
+            .insert_variable(b"fixture-test", Cow::Borrowed(b"1"), VarType::Common)
+            .unwrap();
+        let written = nv.serialize().unwrap();
+        assert_eq!(written.len(), data.len(), "{}", name);
+
+        let mut nv = nvram_parse(&written).unwrap();
+        assert_eq!(nv.active_part_mut().generation(), generation, "{}", name);
+        expected.push((VarType::Common, b"fixture-test".to_vec(), b"1".to_vec()));
+        expected.sort();
+        assert_eq!(sorted_vars(&mut *nv), expected, "{}", name);
+        // and once written, it round trips like any other image
+        assert_same_image(&name, &nv.serialize().unwrap(), &written);
+    }
+}
+
+#[test]
+fn test_binary_values_survive_write() {
+    let value = [&[0; 300][..], b"\xff\xff\x01", &[0xFF; 130], b"\0"].concat();
+    for (name, data) in fixtures() {
+        let mut nv = nvram_parse(&data).unwrap();
+        nv.prepare_for_write();
+        nv.active_part_mut()
+            .insert_variable(b"fixture-binary", Cow::Borrowed(&value), VarType::Common)
+            .unwrap();
+        let written = nv.serialize().unwrap();
+
+        let mut nv = nvram_parse(&written).unwrap();
+        let var = nv
+            .active_part_mut()
+            .get_variable(b"fixture-binary", VarType::Common)
+            .map(|v| v.value().into_owned());
+        assert_eq!(var.as_deref(), Some(&value[..]), "{}", name);
+        assert_same_image(&name, &nv.serialize().unwrap(), &written);
+    }
+}
+
+// v1v2 values can't hold the NUL that ends them, so runs of 0x00 and 0xFF
+// are stored as 0xFF and a count, the top bit set for 0xFF
+#[test]
+fn test_v1v2_escaped_runs() {
+    let data = fs::read(fixture_dir().join("v1v2-binary.bin")).unwrap();
+    let stored = b"binary-test=a\xff\x7f\xff\x49b\xff\x83c\0";
+    assert!(data.windows(stored.len()).any(|w| w == stored));
+    let mut nv = nvram_parse(&data).unwrap();
+    let var = nv
+        .active_part_mut()
+        .get_variable(b"binary-test", VarType::Common)
+        .map(|v| v.value().into_owned());
+    let expected = [&b"a"[..], &[0; 200], b"b", &[0xFF; 3], b"c"].concat();
+    assert_eq!(var, Some(expected));
+}
@@ -196,10 +196,8 @@ fn scan_volume(disk: &mut File) -> io::Result<HashMap<Uuid, Vec<Volume>>> {
     for i in 0..sb.xp_desc_blocks() {
         let mut sbc = NxSuperblock::new();
         pread(disk, (sb.xp_desc_base() + i as u64) * block_size, sbc.get_buf())?;
-        if sbc.magic() == NxSuperblock::MAGIC {
-            if sbc.xid() > sb.xid() {
-                sb = sbc;
-            }
+        if sbc.magic() == NxSuperblock::MAGIC && sbc.xid() > sb.xid() {
+            sb = sbc;
         }
     }
     let mut omap_bytes = vec![0; OmapPhys::SIZE];
@@ -264,14 +262,21 @@ pub enum Error {
     Ambiguous,
     NvramReadError(std::io::Error),
     DiskReadError(std::io::Error),
+    VariableNotFound,
 }
 
 impl From<apple_nvram::Error> for Error {
     fn from(e: apple_nvram::Error) -> Self {
         match e {
             apple_nvram::Error::ParseError => Error::Parse,
-            apple_nvram::Error::SectionTooBig => Error::SectionTooBig,
+            apple_nvram::Error::SectionTooBig | apple_nvram::Error::VariableTooBig(_) => {
+                Error::SectionTooBig
+            }
             apple_nvram::Error::ApplyError(e) => Error::ApplyError(e),
+            apple_nvram::Error::VariableNotFound => Error::VariableNotFound,
+            apple_nvram::Error::InvalidEscape => Error::Parse,
+            apple_nvram::Error::Anomaly(_) => Error::Parse,
+            apple_nvram::Error::InvalidLayout(_) => Error::Parse,
         }
     }
 }
@@ -299,7 +304,7 @@ pub fn get_boot_candidates() -> Result<Vec<BootCandidate>> {
         }
     }
 
-    return Ok(cands);
+    Ok(cands)
 }
 
 pub fn get_boot_volume(next: bool) -> Result<BootCandidate> {
@@ -313,17 +318,16 @@ pub fn get_boot_volume(next: bool) -> Result<BootCandidate> {
     let mut nv = nvram_parse(&data)?;
 
     let active = nv.active_part_mut();
-    let v;
-    if next {
-        v = active
+    let v = if next {
+        active
             .get_variable(b"alt-boot-volume", VarType::System)
             .or(active.get_variable(b"boot-volume", VarType::System))
-            .ok_or(Error::Parse);
+            .ok_or(Error::Parse)
     } else {
-        v = active
+        active
             .get_variable(b"boot-volume", VarType::System)
-            .ok_or(Error::Parse);
-    }
+            .ok_or(Error::Parse)
+    };
     let data = String::from_utf8(v?.value().deref().to_vec()).unwrap();
     let [_, part_uuid, part_vg_uuid]: [&str; 3] =
         data.split(":").collect::<Vec<&str>>().try_into().unwrap();
@@ -362,7 +366,7 @@ pub fn set_boot_volume(cand: &BootCandidate, next: bool) -> Result<()> {
         nvram_key,
         Cow::Owned(boot_str.into_bytes()),
         VarType::System,
-    );
+    )?;
     nv.apply(&mut MtdWriter::new(file))?;
     Ok(())
 }
@@ -53,7 +53,8 @@ fn error_to_string(e: Error) -> String {
         Error::SectionTooBig => "Ran out of space on nvram".to_string(),
         Error::ApplyError(e) => format!("Failed to save new nvram contents, try running with sudo? Inner error: {:?}", e),
         Error::NvramReadError(e) => format!("Failed to read nvram contents, try running with sudo? Inner error: {:?}", e),
-        Error::DiskReadError(e) => format!("Failed to collect boot candidates, try running with sudo? Inner error: {:?}", e)
+        Error::DiskReadError(e) => format!("Failed to collect boot candidates, try running with sudo? Inner error: {:?}", e),
+        Error::VariableNotFound => "Variable not found in nvram".to_string()
     }
 }
 
@@ -90,7 +91,7 @@ fn real_main() -> Result<()> {
             })
             .collect();
         if macos_cands.len() == 1 {
-            set_boot_volume_by_ref(&macos_cands[0], &args, false)?;
+            set_boot_volume_by_ref(macos_cands[0], &args, false)?;
         } else {
             return Err(Error::Ambiguous);
         }
@@ -137,10 +138,8 @@ fn set_boot_volume_by_ref(cand: &BootCandidate, args: &Args, interactive: bool)
     if !interactive {
         println!("Will set volume {} as boot target", get_vg_name(&cand.volumes));
     }
-    if !args.autoconfirm && !interactive {
-        if !confirm() {
-            return Ok(());
-        }
+    if !args.autoconfirm && !interactive && !confirm() {
+        return Ok(());
     }
     set_boot_volume(cand, args.next)?;
     Ok(())
@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: MIT
+// Just enough JSON to read back what `export` produces.
+use std::{iter::Peekable, str::Chars};
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+pub fn string(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if c.is_control() => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+pub fn parse(s: &str) -> Option<Value> {
+    let mut chars = s.chars().peekable();
+    let v = parse_value(&mut chars)?;
+    skip_ws(&mut chars);
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(v)
+}
+
+fn skip_ws(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+fn expect_word(chars: &mut Peekable<Chars>, word: &str) -> Option<()> {
+    for c in word.chars() {
+        chars.next_if_eq(&c)?;
+    }
+    Some(())
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Option<Value> {
+    skip_ws(chars);
+    match *chars.peek()? {
+        'n' => expect_word(chars, "null").map(|_| Value::Null),
+        't' => expect_word(chars, "true").map(|_| Value::Bool(true)),
+        'f' => expect_word(chars, "false").map(|_| Value::Bool(false)),
+        '"' => parse_string(chars).map(Value::String),
+        '[' => {
+            chars.next();
+            let mut items = Vec::new();
+            skip_ws(chars);
+            if chars.next_if_eq(&']').is_some() {
+                return Some(Value::Array(items));
+            }
+            loop {
+                items.push(parse_value(chars)?);
+                skip_ws(chars);
+                match chars.next()? {
+                    ',' => continue,
+                    ']' => return Some(Value::Array(items)),
+                    _ => return None,
+                }
+            }
+        }
+        '{' => {
+            chars.next();
+            let mut entries = Vec::new();
+            skip_ws(chars);
+            if chars.next_if_eq(&'}').is_some() {
+                return Some(Value::Object(entries));
+            }
+            loop {
+                skip_ws(chars);
+                let key = parse_string(chars)?;
+                skip_ws(chars);
+                chars.next_if_eq(&':')?;
+                entries.push((key, parse_value(chars)?));
+                skip_ws(chars);
+                match chars.next()? {
+                    ',' => continue,
+                    '}' => return Some(Value::Object(entries)),
+                    _ => return None,
+                }
+            }
+        }
+        _ => {
+            let mut num = String::new();
+            while let Some(c) =
+                chars.next_if(|c| matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+            {
+                num.push(c);
+            }
+            num.parse().ok().map(Value::Number)
+        }
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    chars.next_if_eq(&'"')?;
+    let mut ret = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(ret),
+            '\\' => match chars.next()? {
+                '"' => ret.push('"'),
+                '\\' => ret.push('\\'),
+                '/' => ret.push('/'),
+                'b' => ret.push('\u{8}'),
+                'f' => ret.push('\u{c}'),
+                'n' => ret.push('\n'),
+                'r' => ret.push('\r'),
+                't' => ret.push('\t'),
+                'u' => {
+                    let hex: String = (0..4).filter_map(|_| chars.next()).collect();
+                    ret.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                _ => return None,
+            },
+            c => ret.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let v = parse(r#" {"a": [1, "x\"\\A", true, null], "b": {}} "#).unwrap();
+        assert_eq!(
+            v,
+            Value::Object(vec![
+                (
+                    "a".to_owned(),
+                    Value::Array(vec![
+                        Value::Number(1.0),
+                        Value::String("x\"\\A".to_owned()),
+                        Value::Bool(true),
+                        Value::Null,
+                    ])
+                ),
+                ("b".to_owned(), Value::Object(Vec::new())),
+            ])
+        );
+        assert!(parse("[1,]").is_none());
+        assert!(parse("{\"a\" 1}").is_none());
+        assert!(parse("\"abc").is_none());
+    }
+
+    #[test]
+    fn test_string_round_trip() {
+        let s = "q\"\\\u{1}x";
+        assert_eq!(parse(&string(s)).unwrap(), Value::String(s.to_owned()));
+    }
+}
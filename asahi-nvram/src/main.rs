@@ -1,144 +1,2715 @@
 // SPDX-License-Identifier: MIT
-use std::{borrow::Cow, fs::OpenOptions, io::Read, process::ExitCode};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet},
+    fs::{File, OpenOptions},
+    hash::BuildHasher,
+    io::{stdin, stdout, IsTerminal, Read, Write},
+    os::unix::fs::{FileTypeExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use apple_nvram::{mtd::MtdWriter, nvram_parse, VarType};
+mod json;
+mod logger;
+mod mmap;
+
+use apple_nvram::{
+    escape_value,
+    file::FileWriter,
+    is_nvram_header,
+    mtd::{self, MtdWriter},
+    nvram_fix_checksums, nvram_parse, nvram_parse_strict, unescape_value,
+    vec::VecWriter,
+    verify::{VerifyError, VerifyingWriter},
+    BankState, Nvram, NvramWriter, OwnedVariable, Partition, VarType, Variable,
+};
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
     Parse,
     SectionTooBig,
+    VariableTooBig(String, usize),
     ApplyError(std::io::Error),
-    MissingPartitionName,
+    VerifyFailed(u64, u8, u8),
     MissingValue,
     VariableNotFound,
-    UnknownPartition,
+    UnknownPartition(String),
     InvalidHex,
+    AmbiguousRawOutput,
+    OutputError(std::io::Error),
+    ValueReadError(std::io::Error),
+    MultipleStdinValues,
+    ConfirmationRequired,
+    ImportReadError(std::io::Error),
+    InvalidImport,
+    BackupWriteError(std::io::Error),
+    BackupReadError(std::io::Error),
+    EditError(std::io::Error),
+    ImageSizeMismatch,
+    ImageTooBig(usize, u64),
+    Anomaly(apple_nvram::Anomaly),
+    ForceRequired,
+    InvalidBank,
+    DeviceOpen(String, std::io::Error),
+    DeviceRead(String, std::io::Error),
+    AmbiguousDevice(Vec<String>),
+    InvalidBootArgs,
+    MalformedBootArgs(&'static str),
+    BatchReadError(std::io::Error),
+    InvalidBatch(usize),
+    InvalidBase64,
+    InvalidUtf16,
+    ProtectedVariable(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Parse => write!(f, "not a valid nvram image"),
+            Error::SectionTooBig => write!(f, "variables don't fit in the nvram partition"),
+            Error::VariableTooBig(name, over) => {
+                write!(
+                    f,
+                    "{} doesn't fit in the nvram partition by {} bytes",
+                    name, over
+                )
+            }
+            Error::ApplyError(e) => write!(f, "failed to write nvram: {}", e),
+            Error::VerifyFailed(offset, written, read) => write!(
+                f,
+                "nvram at {:#x} reads back as {:02x} instead of {:02x}, the flash may be failing",
+                offset, read, written
+            ),
+            Error::MissingValue => write!(f, "expected variable=value"),
+            Error::VariableNotFound => write!(f, "variable not found"),
+            Error::UnknownPartition(name) => write!(
+                f,
+                "unknown partition {:?}, valid partitions are: {}",
+                name,
+                PARTITION_NAMES.join(", ")
+            ),
+            Error::InvalidHex => write!(f, "invalid %XX escape or hex value"),
+            Error::InvalidBase64 => write!(f, "invalid base64 value"),
+            Error::InvalidUtf16 => write!(f, "value isn't valid UTF-16, or UTF-8 to convert to it"),
+            Error::ProtectedVariable(name) => write!(
+                f,
+                "{} is protected, pass --allow-dangerous to change it anyway",
+                name
+            ),
+            Error::AmbiguousRawOutput => write!(f, "--raw needs exactly one variable"),
+            Error::OutputError(e) => write!(f, "failed to write output: {}", e),
+            Error::ValueReadError(e) => write!(f, "failed to read value: {}", e),
+            Error::MultipleStdinValues => write!(f, "only one value can be read from stdin"),
+            Error::ConfirmationRequired => {
+                write!(
+                    f,
+                    "stdin is not a terminal, pass --force to skip confirmation"
+                )
+            }
+            Error::ImportReadError(e) => write!(f, "failed to read import: {}", e),
+            Error::InvalidImport => write!(f, "not a valid export"),
+            Error::BackupWriteError(e) => write!(f, "failed to write backup: {}", e),
+            Error::EditError(e) => write!(f, "failed to edit the value: {}", e),
+            Error::BackupReadError(e) => write!(f, "failed to read image: {}", e),
+            Error::InvalidBank => write!(f, "no valid bank by that name or number"),
+            Error::ImageSizeMismatch => write!(f, "image size doesn't match the device"),
+            Error::Anomaly(a) => write!(f, "not a pristine image with --strict: {}", a),
+            Error::ImageTooBig(size, device) => write!(
+                f,
+                "image is {} bytes but the device only has {}, refusing to write past its end",
+                size, device
+            ),
+            Error::ForceRequired => write!(f, "this can't be undone, pass --force to go ahead"),
+            Error::DeviceOpen(path, e) => write!(f, "cannot open {}: {}", path, e),
+            Error::DeviceRead(path, e) => write!(f, "cannot read {}: {}", path, e),
+            Error::AmbiguousDevice(found) => write!(
+                f,
+                "{} all look like nvram, pick one with --device",
+                found.join(", ")
+            ),
+            Error::InvalidBootArgs => write!(f, "boot-args isn't valid UTF-8"),
+            Error::MalformedBootArgs(why) => write!(
+                f,
+                "boot-args {}, pass --no-validate to write it anyway",
+                why
+            ),
+            Error::BatchReadError(e) => write!(f, "failed to read batch: {}", e),
+            Error::InvalidBatch(line) => write!(
+                f,
+                "batch line {}: expected `set name=value` or `del name`",
+                line
+            ),
+        }
+    }
+}
+
+// 1 is left for checks that ran fine but didn't pass (diff, verify) and 2
+// is what clap exits with on bad usage, 2..=15 are reserved for errors and
+// anything above is free for whatever comes next
+impl Error {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Error::MissingValue
+            | Error::UnknownPartition(_)
+            | Error::InvalidHex
+            | Error::InvalidBase64
+            | Error::InvalidUtf16
+            | Error::AmbiguousRawOutput
+            | Error::MultipleStdinValues
+            | Error::InvalidBank
+            | Error::InvalidBatch(_) => 2,
+            Error::Parse
+            | Error::InvalidImport
+            | Error::ImageSizeMismatch
+            | Error::Anomaly(_)
+            | Error::InvalidBootArgs
+            | Error::MalformedBootArgs(_) => 3,
+            Error::VariableNotFound => 4,
+            Error::SectionTooBig | Error::VariableTooBig(..) => 5,
+            Error::ApplyError(_) | Error::VerifyFailed(..) | Error::ImageTooBig(..) => 6,
+            Error::OutputError(_)
+            | Error::ValueReadError(_)
+            | Error::ImportReadError(_)
+            | Error::BackupWriteError(_)
+            | Error::BackupReadError(_)
+            | Error::EditError(_)
+            | Error::BatchReadError(_) => 7,
+            Error::ConfirmationRequired | Error::ForceRequired | Error::ProtectedVariable(_) => 8,
+            Error::DeviceOpen(..) | Error::DeviceRead(..) | Error::AmbiguousDevice(_) => 9,
+        }
+    }
 }
 
+const EXIT_CODES: &str = "Exit codes:
+    0  success
+    1  diff found differences, verify found a corrupt bank, exists didn't find the variable
+       or grep didn't match anything
+    2  invalid arguments
+    3  not a valid nvram image, import, backup or boot-args value
+    4  variable not found
+    5  variables don't fit in the partition
+    6  writing the nvram failed
+    7  reading or writing another file failed
+    8  confirmation needed, see --force and --allow-dangerous
+    9  the device couldn't be found, opened or read";
+
 impl From<apple_nvram::Error> for Error {
     fn from(e: apple_nvram::Error) -> Self {
         match e {
             apple_nvram::Error::ParseError => Error::Parse,
             apple_nvram::Error::SectionTooBig => Error::SectionTooBig,
-            apple_nvram::Error::ApplyError(e) => Error::ApplyError(e),
+            apple_nvram::Error::VariableTooBig(over) => {
+                Error::VariableTooBig("variable".into(), over)
+            }
+            apple_nvram::Error::ApplyError(e) => {
+                match e.get_ref().and_then(|e| e.downcast_ref::<VerifyError>()) {
+                    Some(v) => Error::VerifyFailed(v.offset, v.written, v.read),
+                    None => Error::ApplyError(e),
+                }
+            }
+            apple_nvram::Error::VariableNotFound => Error::VariableNotFound,
+            apple_nvram::Error::InvalidEscape => Error::InvalidHex,
+            apple_nvram::Error::Anomaly(a) => Error::Anomaly(a),
+            // only the builder fails with it
+            apple_nvram::Error::InvalidLayout(_) => Error::Parse,
         }
     }
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
+static VERBOSITY: AtomicU64 = AtomicU64::new(0);
+static STRICT: AtomicBool = AtomicBool::new(false);
+static VERIFY: AtomicBool = AtomicBool::new(false);
+
+// diagnostics go to stderr so they don't end up in piped output
+macro_rules! verbose {
+    ($level:expr, $($arg:tt)*) => {
+        if VERBOSITY.load(Ordering::Relaxed) >= $level {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+const SYSTEM_GUID: &str = "40A0DDD2-77F8-4392-B4A3-1E7304206516";
+
 fn main() -> ExitCode {
     match real_main() {
-        Ok(_) => ExitCode::SUCCESS,
+        Ok(code) => code,
         Err(e) => {
-            eprintln!("{:?}", e);
-            ExitCode::FAILURE
+            eprintln!("error: {}", e);
+            ExitCode::from(e.exit_code())
         }
     }
 }
 
-fn real_main() -> Result<()> {
-    let matches = clap::command!()
-        .arg(clap::arg!(-d --device [DEVICE] "Path to the nvram device."))
+fn real_main() -> Result<ExitCode> {
+    logger::init();
+    let mut cmd = clap::command!()
+        .after_help(EXIT_CODES)
+        .arg(
+            clap::arg!(-d --device [DEVICE] "Path to the nvram device, - for stdin, found among /dev/mtd* if not given, or /dev/mtd0.")
+                .env("ASAHI_NVRAM_DEVICE"),
+        )
+        .arg(
+            clap::arg!(--"dry-run" "Output the modified nvram image instead of writing it.")
+                .global(true),
+        )
+        .arg(
+            clap::arg!(-o --output [FILE] "Save the modified image here, leaving the device alone, - for stdout.")
+                .global(true),
+        )
+        .arg(
+            clap::arg!(--"default-partition" [PARTITION] "Partition for names without a prefix.")
+                .value_parser(PARTITION_NAMES)
+                .ignore_case(true)
+                .default_value("common"),
+        )
+        .arg(
+            clap::arg!(--incremental "Only erase and write the blocks that changed.")
+                .global(true),
+        )
+        .arg(clap::arg!(--verify "Read back everything written and fail if it differs.").global(true))
+        .arg(
+            clap::arg!(--"backup-dir" [DIR] "Save the device contents here before changing it.")
+                .global(true),
+        )
+        .arg(
+            clap::arg!(--strict "Fail on a corrupt bank, duplicate names or stray data instead of getting past them.")
+                .global(true),
+        )
+        .arg(clap::arg!(-v --verbose ... "Log what is being done to stderr, twice for more."))
         .subcommand(
             clap::Command::new("read")
                 .about("Read nvram variables")
+                .arg(clap::arg!(--bank [BANK] "Read from active (default), inactive or a bank number."))
+                .arg(
+                    clap::arg!(--format [FORMAT] "Output format.")
+                        .value_parser(["text", "json", "plist", "chrp", "hex", "env"])
+                        .default_value("text"),
+                )
+                .arg(
+                    clap::arg!(plist: -x "Output as an XML property list, same as --format plist.")
+                        .conflicts_with("format"),
+                )
+                .arg(
+                    clap::arg!(--raw "Write the value of a single variable to stdout as is.")
+                        .conflicts_with_all(&["format", "plist"]),
+                )
+                .arg(
+                    clap::arg!(--hex "Print a hexdump of each value.")
+                        .conflicts_with_all(&["format", "plist", "raw"]),
+                )
+                .arg(
+                    clap::arg!(--"names-only" "Only print the names, common: alone lists a partition.")
+                        .conflicts_with_all(&["format", "plist", "raw", "hex"]),
+                )
+                .arg(
+                    clap::arg!(--base64 "Print values as base64 instead of %XX-escaped.")
+                        .conflicts_with_all(&["format", "plist", "raw", "hex", "names-only"]),
+                )
+                .arg(
+                    clap::arg!(--utf16 "Decode values from UTF-16, little endian unless there is a BOM.")
+                        .conflicts_with_all(&["format", "plist", "raw", "hex", "names-only", "base64"]),
+                )
+                .arg(clap::arg!(--sort "Sort by partition and name instead of storage order."))
+                .arg(
+                    clap::arg!(null: -'0' --null "Print each name and value as is, ended by NULs.")
+                        .conflicts_with_all(&["format", "plist", "raw", "hex", "base64", "utf16"]),
+                )
+                .arg(
+                    clap::arg!(--count "Only print how many variables there are.").conflicts_with_all(&[
+                        "format", "plist", "raw", "hex", "names-only", "base64", "utf16", "null",
+                    ]),
+                )
+                .arg(
+                    clap::arg!(--bytes "With --count, also print how many bytes their values take.")
+                        .requires("count"),
+                )
+                .arg(
+                    clap::arg!(--color [WHEN] "Color the text output, auto is only on a terminal without NO_COLOR.")
+                        .value_parser(["auto", "always", "never"])
+                        .default_value("auto"),
+                )
+                .arg(
+                    clap::arg!(--decode "Show the values of well-known variables as what they mean, as `name (decoded)=value`.")
+                        .conflicts_with_all(&[
+                            "format", "plist", "raw", "hex", "names-only", "base64", "utf16", "null", "count",
+                        ]),
+                )
                 .arg(clap::Arg::new("variable").multiple_values(true)),
         )
+        .subcommand(
+            clap::Command::new("clear")
+                .about("Delete every variable in a partition")
+                .arg(clap::arg!(--force "Required, as there is no undoing this."))
+                .arg(clap::arg!(<partition> "Partition to clear.")),
+        )
         .subcommand(
             clap::Command::new("delete")
                 .about("Delete nvram variables")
+                .arg(clap::arg!(-y --force "Do not ask for confirmation."))
+                .arg(clap::arg!(--"allow-dangerous" "Allow deleting variables needed to boot."))
                 .arg(clap::Arg::new("variable").multiple_values(true)),
         )
+        .subcommand(
+            clap::Command::new("rename")
+                .about("Give a variable a new name, possibly in the other partition")
+                .arg(clap::arg!(-y --force "Do not ask for confirmation, replace <to> if it exists."))
+                .arg(clap::arg!(--"allow-dangerous" "Allow changing variables needed to boot."))
+                .arg(clap::arg!(<from> "The variable to rename."))
+                .arg(clap::arg!(<to> "Its new name.")),
+        )
+        .subcommand(
+            clap::Command::new("append")
+                .about("Add bytes to the end of variables, creating them if needed")
+                .arg(clap::arg!(-y --force "Do not ask for confirmation."))
+                .arg(clap::arg!(--"allow-dangerous" "Allow changing variables needed to boot."))
+                .arg(clap::Arg::new("variable=value").multiple_values(true).help(
+                    "Values are %XX-escaped, use @path to read one from a file or - for stdin.",
+                )),
+        )
+        .subcommand(
+            clap::Command::new("copy")
+                .about("Copy a variable, possibly to the other partition")
+                .arg(clap::arg!(-y --force "Do not ask for confirmation, replace <to> if it exists."))
+                .arg(clap::arg!(--"allow-dangerous" "Allow changing variables needed to boot."))
+                .arg(clap::arg!(<from> "The variable to copy."))
+                .arg(clap::arg!(<to> "Where to copy it.")),
+        )
+        .subcommand(
+            clap::Command::new("backup")
+                .about("Copy the raw nvram contents to a file")
+                .arg(clap::arg!(-f --force "Overwrite the file if it exists."))
+                .arg(clap::arg!(<file> "Where to save the backup.")),
+        )
+        .subcommand(
+            clap::Command::new("batch")
+                .about("Apply a list of changes with a single write")
+                .arg(clap::arg!(-y --force "Do not ask for confirmation."))
+                .arg(clap::arg!(--"allow-dangerous" "Allow changing variables needed to boot."))
+                .arg(clap::arg!([file] "One `set name=value` or `del name` per line, - for stdin.")),
+        )
+        .subcommand(
+            clap::Command::new("boot-args")
+                .about("Print or change the kernel boot arguments")
+                .arg(clap::arg!(-y --force "Do not ask for confirmation."))
+                .arg(
+                    clap::arg!(--add <FLAG> "Add a flag, replacing any with the same name.")
+                        .required(false)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::arg!(--remove <FLAG> "Remove a flag, with or without its =value.")
+                        .required(false)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::arg!([value] "Replace the boot arguments entirely.")
+                        .conflicts_with_all(&["add", "remove"]),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("diff")
+                .about("Compare the variables in two nvram images")
+                .arg(clap::arg!(<old> "Original image."))
+                .arg(clap::arg!(<new> "Modified image.")),
+        )
+        .subcommand(
+            clap::Command::new("fix-crc")
+                .about("Recompute checksums that don't match their data")
+                .arg(clap::arg!(--force "Write the fixed checksums, trusting the data as is.")),
+        )
+        .subcommand(
+            clap::Command::new("edit")
+                .about("Edit a value in $VISUAL or $EDITOR, in its %XX-escaped form")
+                .arg(clap::arg!(--"allow-dangerous" "Allow changing variables needed to boot."))
+                .arg(clap::arg!(--"no-validate" "Write boot-args even if it looks malformed."))
+                .arg(clap::arg!(<variable> "Variable to edit, it's created if it doesn't exist.")),
+        )
+        .subcommand(clap::Command::new("export").about("Dump all nvram partitions as JSON"))
+        .subcommand(
+            clap::Command::new("import")
+                .about("Replace nvram contents with a JSON export")
+                .arg(clap::arg!(-y --force "Do not ask for confirmation."))
+                .arg(
+                    clap::arg!(--partition [PARTITION] "Only import this partition.")
+                        .value_parser(PARTITION_NAMES)
+                        .ignore_case(true),
+                )
+                .arg(clap::arg!(<file> "File produced by export.")),
+        )
+        .subcommand(
+            clap::Command::new("exists")
+                .about("Exit with 0 if a variable is set and 1 if it isn't, printing nothing")
+                .arg(clap::arg!(<variable> "The variable to look for.")),
+        )
+        .subcommand(
+            clap::Command::new("grep")
+                .about("Print the variables whose value contains a pattern, exit with 1 if none do")
+                .arg(clap::arg!(--name "Match the names too."))
+                .arg(clap::arg!(-i --"ignore-case" "Ignore the case of ASCII letters."))
+                .arg(clap::arg!(<pattern> "Bytes to look for, %XX-escaped.")),
+        )
+        .subcommand(
+            clap::Command::new("info")
+                .about("Summarize the image: version, banks and space used")
+                .arg(clap::arg!(--json "Output as JSON.")),
+        )
+        .subcommand(
+            clap::Command::new("list-partitions")
+                .about("List nvram partitions, marking the active one"),
+        )
+        .subcommand(
+            clap::Command::new("restore")
+                .about("Write a raw nvram backup back to the device")
+                .arg(clap::arg!(-y --force "Do not ask for confirmation."))
+                .arg(clap::arg!(<file> "Backup to restore.")),
+        )
+        .subcommand(
+            clap::Command::new("usage")
+                .about("Show the space used in each partition and what uses it")
+                .arg(
+                    clap::arg!(--sort [KEY] "Order of the variables.")
+                        .value_parser(["size", "name"])
+                        .default_value("size"),
+                )
+                .arg(
+                    clap::arg!(--top [N] "How many variables to list per partition, 0 for all.")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("verify")
+                .about("Check the integrity of every nvram bank")
+                .arg(clap::arg!(--bank [BANK] "Only check this bank.")),
+        )
         .subcommand(
             clap::Command::new("write")
                 .about("Write nvram variables")
-                .arg(clap::Arg::new("variable=value").multiple_values(true)),
-        )
-        .get_matches();
-    let default_name = "/dev/mtd0".to_owned();
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(matches.get_one::<String>("device").unwrap_or(&default_name))
-        .unwrap();
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).unwrap();
-    let mut nv = nvram_parse(&data)?;
+                .arg(clap::arg!(-y --force "Do not ask for confirmation."))
+                .arg(clap::arg!(--"allow-dangerous" "Allow changing variables needed to boot."))
+                .arg(clap::arg!(--"no-validate" "Write boot-args even if it looks malformed."))
+                .arg(clap::arg!(--"no-hex-prefix" "Don't treat values starting with 0x as hex bytes."))
+                .arg(clap::arg!(--base64 "Values, and files or stdin they come from, are base64."))
+                .arg(
+                    clap::arg!(--hex "Values, and files or stdin they come from, are hex digits.")
+                        .conflicts_with("base64"),
+                )
+                .arg(
+                    clap::arg!(--utf16 "Store values as UTF-16LE, without a BOM.")
+                        .conflicts_with_all(&["base64", "hex"]),
+                )
+                .arg(
+                    clap::arg!(--generation [N] "Write this generation instead of the next one. The firmware reads the \
+                         bank with the highest, one that isn't above the others' can leave it reading an older bank. \
+                         Below 4294967295, so the next write still has a higher one to go to.")
+                        // the firmware doesn't compare generations across the wrap
+                        .value_parser(clap::value_parser!(u32).range(..i64::from(u32::MAX))),
+                )
+                .arg(
+                    clap::arg!(--partition [PARTITION] "Partition for names without a prefix, both writes them to each.")
+                        .value_parser(["common", "system", "both"])
+                        .ignore_case(true),
+                )
+                .arg(clap::Arg::new("variable=value").multiple_values(true).help(
+                    "Values are %XX-escaped, use @path to read one from a file or - for stdin. \
+                 0x followed by hex digits gives those bytes, %30x is a literal 0x.",
+                )),
+        );
+    let matches = cmd.get_matches_mut();
+    if let Some(("diff", args)) = matches.subcommand() {
+        return diff(
+            args.get_one::<String>("old").unwrap(),
+            args.get_one::<String>("new").unwrap(),
+        );
+    }
+    VERBOSITY.store(matches.occurrences_of("verbose"), Ordering::Relaxed);
+    STRICT.store(matches.contains_id("strict"), Ordering::Relaxed);
+    VERIFY.store(matches.contains_id("verify"), Ordering::Relaxed);
+    let default_typ = part_by_name(matches.get_one::<String>("default-partition").unwrap())?;
+    let dry_run = matches.contains_id("dry-run");
+    let modifies = match matches.subcommand() {
+        Some((
+            "write" | "append" | "delete" | "rename" | "copy" | "batch" | "clear" | "import"
+            | "restore" | "fix-crc" | "edit",
+            _,
+        )) => true,
+        // without anything to change it only prints them
+        Some(("boot-args", args)) => ["value", "add", "remove"]
+            .iter()
+            .any(|a| args.contains_id(a)),
+        _ => false,
+    };
+    let output = matches.get_one::<String>("output");
+    // clap doesn't check conflicts between global args used at different levels
+    if dry_run && output.is_some() {
+        cmd.error(
+            clap::ErrorKind::ArgumentConflict,
+            "--dry-run and --output can't be used together",
+        )
+        .exit();
+    }
+    let writable = modifies && !dry_run && output.is_none();
+    let device = match matches.get_one::<String>("device") {
+        Some(device) => device.clone(),
+        None => find_device()?,
+    };
+    verbose!(1, "using {}", device);
+    // `-` reads the image from stdin, which can't be written back to
+    let mut file = if device == "-" {
+        if writable {
+            cmd.error(
+                clap::ErrorKind::ArgumentConflict,
+                "--device - needs --output or --dry-run to change anything",
+            )
+            .exit();
+        }
+        None
+    } else {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(&device)
+            .map_err(|e| Error::DeviceOpen(device.clone(), e))?;
+        Some(file)
+    };
+    // image files are mapped when only reading, anything that changes the
+    // image, and whatever can't be mapped, gets its own copy
+    let mapping = match &file {
+        Some(file) if !modifies => mmap::Mapping::new(file),
+        _ => None,
+    };
+    let data: Cow<[u8]> = match &mapping {
+        Some(mapping) => {
+            verbose!(2, "mapped {} bytes", mapping.bytes().len());
+            Cow::Borrowed(mapping.bytes())
+        }
+        None => {
+            let mut data = Vec::new();
+            match &mut file {
+                Some(file) => file.read_to_end(&mut data),
+                None => stdin().read_to_end(&mut data),
+            }
+            .map_err(|e| Error::DeviceRead(device.clone(), e))?;
+            verbose!(2, "read {} bytes", data.len());
+            Cow::Owned(data)
+        }
+    };
+    // taken before anything is written, and nothing is if it can't be
+    if let Some(dir) = matches.get_one::<String>("backup-dir").filter(|_| writable) {
+        let path = save_backup(dir.as_ref(), &data).map_err(Error::BackupWriteError)?;
+        verbose!(1, "saved a backup to {}", path.display());
+    }
+    let file = match (modifies, output.map(String::as_str)) {
+        (true, Some("-")) => Dest::Stdout,
+        (true, Some(path)) => {
+            // start from a copy, v3 only writes the bank that changed
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .and_then(|mut f| f.write_all(&data).map(|_| f))
+                .map_err(Error::OutputError)?;
+            Dest::File(file)
+        }
+        // without a file only --dry-run or read-only commands get here,
+        // and neither of them write
+        _ => file.map_or(Dest::Stdout, Dest::File),
+    };
+    let incremental = matches.contains_id("incremental").then_some(&data[..]);
     match matches.subcommand() {
         Some(("read", args)) => {
-            let active = nv.active_part_mut();
+            // decoded before parsing, the names have to outlive the image
+            let mut names = Vec::new();
+            for var in args.get_many::<String>("variable").unwrap_or_default() {
+                let (typ, name) = split_var(var, default_typ)?;
+                // a bare prefix selects everything in it
+                let name = if name.is_empty() { "*" } else { name };
+                names.push((var, typ, Selector::new(name)?));
+            }
+            let nv = parse_image(&data)?;
+            let bank = select_bank(&*nv, args.get_one::<String>("bank"))?;
+            let part = nv.bank(bank).ok_or(Error::InvalidBank)?;
 
-            let vars = args.get_many::<String>("variable");
-            if let Some(vars) = vars {
-                for var in vars {
-                    let (part, name) = var.split_once(':').ok_or(Error::MissingPartitionName)?;
-                    let typ = part_by_name(part)?;
-                    let v = active
-                        .get_variable(name.as_bytes(), typ)
-                        .ok_or(Error::VariableNotFound)?;
-                    println!("{}", v);
+            let mut vars = if !names.is_empty() {
+                let mut ret = Vec::new();
+                for (var, typ, sel) in &names {
+                    let typ = *typ;
+                    verbose!(1, "{}: {} variable {} in bank {}", var, typ, sel, bank);
+                    if let Selector::Name(name) = sel {
+                        ret.push(part.try_get_variable(name, typ)?);
+                    } else {
+                        let len = ret.len();
+                        ret.extend(
+                            part.variables()
+                                .filter(|v| v.typ() == typ && sel.matches(v.name())),
+                        );
+                        verbose!(1, "{}: {} matches", var, ret.len() - len);
+                        if ret.len() == len {
+                            return Err(Error::VariableNotFound);
+                        }
+                    }
                 }
+                ret
             } else {
-                for var in active.variables() {
-                    println!("{}", var);
+                part.variables().collect()
+            };
+            // the same order export always uses, which also sorts the lines
+            if args.contains_id("sort") {
+                vars.sort_by_key(|v| (v.typ(), v.name()));
+            }
+            if args.contains_id("count") {
+                if args.contains_id("bytes") {
+                    let bytes: usize = vars.iter().map(|v| v.value().len()).sum();
+                    println!("{} {}", vars.len(), bytes);
+                } else {
+                    println!("{}", vars.len());
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            let format = if args.contains_id("names-only") {
+                "names"
+            } else if args.contains_id("base64") {
+                "base64"
+            } else if args.contains_id("utf16") {
+                "utf16"
+            } else if args.contains_id("null") {
+                "null"
+            } else if args.contains_id("raw") {
+                "raw"
+            } else if args.contains_id("hex") {
+                "hexdump"
+            } else if args.contains_id("plist") {
+                "plist"
+            } else {
+                args.get_one::<String>("format").unwrap().as_str()
+            };
+            match format {
+                "json" => print_json(&vars),
+                "plist" => print_plist(&vars),
+                "chrp" => print_chrp(&vars)?,
+                "env" => {
+                    for var in vars {
+                        println!("{}", env_line(var.name(), &var.value()));
+                    }
+                }
+                "raw" => print_raw(&vars)?,
+                "names" if args.contains_id("null") => {
+                    let mut buf = Vec::new();
+                    for var in vars {
+                        buf.extend_from_slice(format!("{}:", var.typ()).as_bytes());
+                        buf.extend_from_slice(var.name());
+                        buf.push(0);
+                    }
+                    write_stdout(&buf)?;
+                }
+                "names" => {
+                    for var in vars {
+                        println!("{}:{}", var.typ(), escape_value(var.name()));
+                    }
+                }
+                "null" => {
+                    let mut buf = Vec::new();
+                    for var in vars {
+                        buf.extend_from_slice(format!("{}:", var.typ()).as_bytes());
+                        buf.extend_from_slice(var.name());
+                        buf.push(0);
+                        buf.extend_from_slice(&var.value());
+                        buf.push(0);
+                    }
+                    write_stdout(&buf)?;
+                }
+                "base64" => {
+                    for var in vars {
+                        let value = base64_encode(&var.value());
+                        println!("{}:{}={}", var.typ(), escape_value(var.name()), value);
+                    }
+                }
+                "hex" => {
+                    for var in vars {
+                        let value = hex_encode(&var.value());
+                        println!("{}:{}={}", var.typ(), escape_value(var.name()), value);
+                    }
+                }
+                "utf16" => {
+                    for var in vars {
+                        let mut line = Vec::new();
+                        chrp_escape(utf16_decode(&var.value())?.as_bytes(), &mut line);
+                        let value = String::from_utf8(line).unwrap();
+                        println!("{}:{}={}", var.typ(), escape_value(var.name()), value);
+                    }
+                }
+                "hexdump" => {
+                    for var in vars {
+                        println!("{}:{}", var.typ(), escape_value(var.name()));
+                        print!("{}", hexdump(&var.value()));
+                    }
+                }
+                _ => {
+                    let color = match args.get_one::<String>("color").unwrap().as_str() {
+                        "always" => true,
+                        "never" => false,
+                        _ => {
+                            stdout().is_terminal()
+                                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                        }
+                    };
+                    for var in vars {
+                        let line = args
+                            .contains_id("decode")
+                            .then(|| decoded_line(var))
+                            .flatten()
+                            .unwrap_or_else(|| var.to_string());
+                        match color {
+                            true => println!("{}", colorize(&line)),
+                            false => println!("{}", line),
+                        }
+                    }
                 }
             }
         }
-        Some(("write", args)) => {
-            let vars = args.get_many::<String>("variable=value");
+        Some(("boot-args", args)) => {
+            let mut nv = parse_image(&data)?;
+            let current = match nv
+                .active_part_mut()
+                .get_variable(b"boot-args", VarType::Common)
+            {
+                Some(var) => Some(var.as_str().ok_or(Error::InvalidBootArgs)?.into_owned()),
+                None => None,
+            };
+            let new = if let Some(value) = args.get_one::<String>("value") {
+                value.clone()
+            } else if modifies {
+                edit_boot_args(
+                    current.as_deref().unwrap_or(""),
+                    args.get_many::<String>("add").unwrap_or_default(),
+                    args.get_many::<String>("remove").unwrap_or_default(),
+                )
+            } else {
+                println!("{}", current.ok_or(Error::VariableNotFound)?);
+                return Ok(ExitCode::SUCCESS);
+            };
+            let changes = [format!("set common:boot-args to {:?}", new)];
+            verbose!(1, "{}", changes[0]);
+            nv.prepare_for_write();
+            nv.active_part_mut()
+                .insert_variable(b"boot-args", Cow::Owned(new.into_bytes()), VarType::Common)
+                .map_err(|e| too_big(e, VarType::Common, b"boot-args"))?;
+            if dry_run || confirm(&changes, args.contains_id("force"))? {
+                commit(&mut *nv, file, dry_run, incremental)?;
+            }
+        }
+        Some(("batch", args)) => {
+            let batch = match args.get_one::<String>("file").map(String::as_str) {
+                None | Some("-") => {
+                    let mut ret = String::new();
+                    stdin()
+                        .read_to_string(&mut ret)
+                        .map(|_| ret)
+                        .map_err(Error::BatchReadError)?
+                }
+                Some(path) => std::fs::read_to_string(path).map_err(Error::BatchReadError)?,
+            };
+            // every line is checked before anything is applied
+            let ops = parse_batch(&batch, default_typ)?;
+            for op in &ops {
+                let (BatchOp::Set(typ, name, _) | BatchOp::Delete(typ, name)) = op;
+                check_protected(*typ, name, args.contains_id("allow-dangerous"))?;
+            }
+            let mut nv = parse_image(&data)?;
             nv.prepare_for_write();
             let active = nv.active_part_mut();
-            for var in vars.unwrap_or_default() {
+            let mut changes = Vec::new();
+            for op in &ops {
+                match op {
+                    BatchOp::Set(typ, name, value) => {
+                        changes.push(format!(
+                            "set {}:{} ({} bytes)",
+                            typ,
+                            escape_value(name),
+                            value.len()
+                        ));
+                        active
+                            .insert_variable(name, Cow::Borrowed(value), *typ)
+                            .map_err(|e| too_big(e, *typ, name))?;
+                    }
+                    BatchOp::Delete(typ, name) => {
+                        changes.push(format!("delete {}:{}", typ, escape_value(name)));
+                        active.remove_variable(name, *typ);
+                    }
+                }
+                verbose!(1, "{}", changes.last().unwrap());
+            }
+            if dry_run || confirm(&changes, args.contains_id("force"))? {
+                commit(&mut *nv, file, dry_run, incremental)?;
+            }
+        }
+        Some(("append", args)) => {
+            let vars: Vec<_> = args
+                .get_many::<String>("variable=value")
+                .unwrap_or_default()
+                .collect();
+            if vars.iter().filter(|v| reads_stdin(v)).count() > 1 {
+                return Err(Error::MultipleStdinValues);
+            }
+            let mut decoded = Vec::new();
+            for var in vars {
                 let (key, value) = var.split_once('=').ok_or(Error::MissingValue)?;
-                let (part, name) = key.split_once(':').ok_or(Error::MissingPartitionName)?;
-                let typ = part_by_name(part)?;
-                active.insert_variable(name.as_bytes(), Cow::Owned(read_var(value)?), typ);
+                let (typ, name) = split_var(key, default_typ)?;
+                let name = read_var(name)?;
+                check_protected(typ, &name, args.contains_id("allow-dangerous"))?;
+                decoded.push((typ, name, read_value(value)?));
+            }
+            let mut nv = parse_image(&data)?;
+            nv.prepare_for_write();
+            let active = nv.active_part_mut();
+            let mut changes = Vec::new();
+            for (typ, name, value) in &decoded {
+                let escaped = escape_value(name);
+                changes.push(format!(
+                    "append {} bytes to {}:{}",
+                    value.len(),
+                    typ,
+                    escaped
+                ));
+                verbose!(1, "append {} bytes to {}:{}", value.len(), typ, escaped);
+                // the same variable can be appended to more than once
+                let mut combined = active
+                    .get_variable(name, *typ)
+                    .map_or_else(Vec::new, |v| v.value().into_owned());
+                combined.extend_from_slice(value);
+                active
+                    .insert_variable(name, Cow::Owned(combined), *typ)
+                    .map_err(|e| too_big(e, *typ, name))?;
+            }
+            if dry_run || confirm(&changes, args.contains_id("force"))? {
+                commit(&mut *nv, file, dry_run, incremental)?;
+            }
+        }
+        Some(("write", args)) => {
+            let vars: Vec<_> = args
+                .get_many::<String>("variable=value")
+                .unwrap_or_default()
+                .collect();
+            // there is only one stdin to go around
+            if vars.iter().filter(|v| reads_stdin(v)).count() > 1 {
+                return Err(Error::MultipleStdinValues);
+            }
+            let default_types = match args.get_one::<String>("partition") {
+                Some(p) if p.eq_ignore_ascii_case("both") => vec![VarType::Common, VarType::System],
+                Some(p) => vec![part_by_name(p)?],
+                None => vec![default_typ],
+            };
+            let mut decoded = Vec::new();
+            for var in vars {
+                let (key, value) = var.split_once('=').ok_or(Error::MissingValue)?;
+                let (types, name) = split_var_types(key, &default_types)?;
+                let hex = value
+                    .strip_prefix("0x")
+                    .filter(|_| !args.contains_id("no-hex-prefix"));
+                let value = if args.contains_id("base64") {
+                    read_base64(value)?
+                } else if args.contains_id("hex") {
+                    read_hex(value)?
+                } else if args.contains_id("utf16") {
+                    let text =
+                        String::from_utf8(read_value(value)?).map_err(|_| Error::InvalidUtf16)?;
+                    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+                } else if let Some(hex) = hex {
+                    parse_hex(hex)?
+                } else {
+                    read_value(value)?
+                };
+                let name = read_var(name)?;
+                for typ in types {
+                    check_protected(typ, &name, args.contains_id("allow-dangerous"))?;
+                    if typ == VarType::Common
+                        && name == b"boot-args"
+                        && !args.contains_id("no-validate")
+                    {
+                        validate_boot_args(&value).map_err(Error::MalformedBootArgs)?;
+                    }
+                    decoded.push((typ, name.clone(), value.clone()));
+                }
+            }
+            let mut nv = parse_image(&data)?;
+            nv.prepare_for_write();
+            let mut changes = Vec::new();
+            if let Some(&generation) = args.get_one::<u32>("generation") {
+                changes.push(format!("set the generation to {}", generation));
+                verbose!(
+                    1,
+                    "generation {} instead of {}",
+                    generation,
+                    nv.generation()
+                );
+                nv.set_generation(generation);
+            }
+            let active = nv.active_part_mut();
+            for (typ, name, value) in &decoded {
+                let escaped = escape_value(name);
+                changes.push(format!("set {}:{} ({} bytes)", typ, escaped, value.len()));
+                verbose!(1, "set {}:{} ({} bytes)", typ, escaped, value.len());
+                active
+                    .insert_variable(name, Cow::Borrowed(value), *typ)
+                    .map_err(|e| too_big(e, *typ, name))?;
+            }
+            if dry_run || confirm(&changes, args.contains_id("force"))? {
+                commit(&mut *nv, file, dry_run, incremental)?;
             }
-            nv.apply(&mut MtdWriter::new(file))?;
         }
         Some(("delete", args)) => {
-            let vars = args.get_many::<String>("variable");
+            let mut names = Vec::new();
+            for var in args.get_many::<String>("variable").unwrap_or_default() {
+                let (typ, name) = split_var(var, default_typ)?;
+                names.push((typ, Selector::new(name)?));
+            }
+            let mut nv = parse_image(&data)?;
+            nv.prepare_for_write();
+            let active = nv.active_part_mut();
+            let mut changes = Vec::new();
+            for (typ, sel) in &names {
+                let typ = *typ;
+                let names: Vec<_> = match sel {
+                    Selector::Name(name) => vec![&name[..]],
+                    sel => {
+                        let names: Vec<_> = active
+                            .variables()
+                            .filter(|v| v.typ() == typ && sel.matches(v.name()))
+                            .map(|v| v.name())
+                            .collect();
+                        if names.is_empty() {
+                            return Err(Error::VariableNotFound);
+                        }
+                        names
+                    }
+                };
+                for name in names {
+                    check_protected(typ, name, args.contains_id("allow-dangerous"))?;
+                    changes.push(format!("delete {}:{}", typ, escape_value(name)));
+                    verbose!(1, "removed {}:{}", typ, escape_value(name));
+                    active.remove_variable(name, typ);
+                }
+            }
+            if dry_run || confirm(&changes, args.contains_id("force"))? {
+                commit(&mut *nv, file, dry_run, incremental)?;
+            }
+        }
+        Some((cmd @ ("rename" | "copy"), args)) => {
+            let (from_typ, from) = split_var(args.get_one::<String>("from").unwrap(), default_typ)?;
+            let (to_typ, to) = split_var(args.get_one::<String>("to").unwrap(), default_typ)?;
+            let (from, to) = (read_var(from)?, read_var(to)?);
+            let rename = cmd == "rename";
+            let allow = args.contains_id("allow-dangerous");
+            check_protected(to_typ, &to, allow)?;
+            if rename {
+                check_protected(from_typ, &from, allow)?;
+            }
+            let mut nv = parse_image(&data)?;
+            nv.prepare_for_write();
+            let active = nv.active_part_mut();
+            let value = active
+                .try_get_variable(&from, from_typ)?
+                .value()
+                .into_owned();
+            if (from_typ, &from) == (to_typ, &to) {
+                return Ok(ExitCode::SUCCESS);
+            }
+            let force = args.contains_id("force");
+            if !dry_run && !force && active.contains(&to, to_typ) {
+                return Err(Error::ForceRequired);
+            }
+            let change = format!(
+                "{} {}:{} to {}:{}",
+                cmd,
+                from_typ,
+                escape_value(&from),
+                to_typ,
+                escape_value(&to)
+            );
+            verbose!(1, "{}", change);
+            // removed first so that a rename within a partition can reuse the space
+            if rename {
+                active.remove_variable(&from, from_typ);
+            }
+            active
+                .insert_variable(&to, Cow::Owned(value), to_typ)
+                .map_err(|e| too_big(e, to_typ, &to))?;
+            if dry_run || confirm(&[change], force)? {
+                commit(&mut *nv, file, dry_run, incremental)?;
+            }
+        }
+        Some(("clear", args)) => {
+            let typ = part_by_name(args.get_one::<String>("partition").unwrap())?;
+            if !dry_run && !args.contains_id("force") {
+                return Err(Error::ForceRequired);
+            }
+            let mut nv = parse_image(&data)?;
+            nv.prepare_for_write();
+            nv.active_part_mut().clear(typ);
+            verbose!(1, "cleared {}", typ);
+            commit(&mut *nv, file, dry_run, incremental)?;
+        }
+        Some(("backup", args)) => {
+            // not parsed on purpose, a corrupt nvram is worth keeping around too
+            let force = args.contains_id("force");
+            OpenOptions::new()
+                .write(true)
+                .create(force)
+                .truncate(force)
+                .create_new(!force)
+                .open(args.get_one::<String>("file").unwrap())
+                .and_then(|mut f| f.write_all(&data))
+                .map_err(Error::BackupWriteError)?;
+        }
+        Some(("edit", args)) => {
+            let (typ, name) = split_var(args.get_one::<String>("variable").unwrap(), default_typ)?;
+            let name = read_var(name)?;
+            check_protected(typ, &name, args.contains_id("allow-dangerous"))?;
+            let validate =
+                typ == VarType::Common && name == b"boot-args" && !args.contains_id("no-validate");
+            let mut nv = parse_image(&data)?;
+            let old = nv
+                .active_part_mut()
+                .get_variable(&name, typ)
+                .map(|v| v.value().into_owned());
+            let text = escape_value(old.as_deref().unwrap_or_default());
+            // leaving it as it was is fine, even if it didn't validate before
+            let check = |value: &[u8]| match validate && old.as_deref() != Some(value) {
+                true => validate_boot_args(value).map_err(Error::MalformedBootArgs),
+                false => Ok(()),
+            };
+            // the editor was the confirmation, saving it is asking for the write
+            let value = match edit_value(&editor(), &text, &check)? {
+                None => {
+                    eprintln!("the value was emptied, nothing written");
+                    return Ok(ExitCode::SUCCESS);
+                }
+                Some(value) if old.as_ref() == Some(&value) => {
+                    eprintln!("the value is unchanged, nothing written");
+                    return Ok(ExitCode::SUCCESS);
+                }
+                Some(value) => value,
+            };
+            verbose!(
+                1,
+                "set {}:{} ({} bytes)",
+                typ,
+                escape_value(&name),
+                value.len()
+            );
+            nv.prepare_for_write();
+            nv.active_part_mut()
+                .insert_variable(&name, Cow::Owned(value), typ)
+                .map_err(|e| too_big(e, typ, &name))?;
+            commit(&mut *nv, file, dry_run, incremental)?;
+        }
+        Some(("export", _args)) => {
+            let nv = parse_image(&data)?;
+            print_export(&*nv);
+        }
+        Some(("import", args)) => {
+            let doc = std::fs::read_to_string(args.get_one::<String>("file").unwrap())
+                .map_err(Error::ImportReadError)?;
+            let types = match args.get_one::<String>("partition") {
+                Some(part) => vec![part_by_name(part)?],
+                None => vec![VarType::Common, VarType::System],
+            };
+            // everything is decoded up front so a bad file can't leave
+            // the nvram half imported
+            let vars: Vec<_> = read_export(&doc)?
+                .into_iter()
+                .filter(|v| types.contains(&v.typ))
+                .collect();
+            let mut nv = parse_image(&data)?;
             nv.prepare_for_write();
             let active = nv.active_part_mut();
-            for var in vars.unwrap_or_default() {
-                let (part, name) = var.split_once(':').ok_or(Error::MissingPartitionName)?;
-                let typ = part_by_name(part)?;
-                active.remove_variable(name.as_bytes(), typ);
+            let mut changes = Vec::new();
+            for &typ in &types {
+                let names: Vec<_> = active
+                    .variables()
+                    .filter(|v| v.typ() == typ)
+                    .map(|v| v.name())
+                    .collect();
+                for name in names {
+                    active.remove_variable(name, typ);
+                }
+                changes.push(format!("clear {}", typ));
+            }
+            for var in &vars {
+                changes.push(format!(
+                    "set {}:{} ({} bytes)",
+                    var.typ,
+                    escape_value(&var.name),
+                    var.value.len()
+                ));
+                active
+                    .insert_variable(&var.name, Cow::Borrowed(&var.value), var.typ)
+                    .map_err(|e| too_big(e, var.typ, &var.name))?;
+            }
+            if dry_run || confirm(&changes, args.contains_id("force"))? {
+                commit(&mut *nv, file, dry_run, incremental)?;
+            }
+        }
+        Some(("restore", args)) => {
+            let path = args.get_one::<String>("file").unwrap();
+            let image = std::fs::read(path).map_err(Error::BackupReadError)?;
+            // make sure this is an nvram image at all
+            parse_image(&image)?;
+            if image.len() != data.len() {
+                return Err(Error::ImageSizeMismatch);
+            }
+            if dry_run {
+                print_image(&image)?;
+            } else if confirm(&[format!("restore {}", path)], args.contains_id("force"))? {
+                // written as is rather than re-serialized
+                write_whole(file, &image)?;
+            }
+        }
+        Some(("fix-crc", args)) => {
+            let mut image = data.to_vec();
+            let fixes = nvram_fix_checksums(&mut image);
+            for fix in &fixes {
+                let line = format!("{:#x}: {:08x} -> {:08x}", fix.offset, fix.old, fix.new);
+                // keep the image on stdout intact, --dry-run prints it there too
+                if dry_run || matches!(file, Dest::Stdout) {
+                    eprintln!("{}", line);
+                } else {
+                    println!("{}", line);
+                }
+            }
+            // a pipeline still gets its image
+            if fixes.is_empty() && !matches!(file, Dest::Stdout) {
+                return Ok(ExitCode::SUCCESS);
+            }
+            // the checksums being right doesn't mean the rest is
+            parse_image(&image)?;
+            if dry_run {
+                print_image(&image)?;
+            } else if args.contains_id("force") {
+                write_whole(file, &image)?;
+            } else {
+                return Err(Error::ForceRequired);
+            }
+        }
+        Some(("verify", args)) => {
+            let nv = parse_image(&data)?;
+            let only = match args.get_one::<String>("bank") {
+                Some(bank) => Some(select_bank(&*nv, Some(bank))?),
+                None => None,
+            };
+            let mut corrupt = false;
+            println!("version: {}", nv.version());
+            println!("active bank: {} ({})", nv.active_bank(), nv.active_reason());
+            for (i, state) in nv.bank_states().into_iter().enumerate() {
+                if only.is_some_and(|only| only != i) {
+                    continue;
+                }
+                let marker = if i == nv.active_bank() { "*" } else { " " };
+                corrupt |= state == BankState::Invalid;
+                match nv.bank(i) {
+                    Some(part) => {
+                        print!(
+                            "{}{}: {}, generation {}",
+                            marker,
+                            i,
+                            state,
+                            part.generation()
+                        );
+                        // the last one is used, but something wrote them twice
+                        if part.duplicates() > 0 {
+                            corrupt = true;
+                            print!(", {} duplicate variables", part.duplicates());
+                        }
+                        println!();
+                    }
+                    None => println!("{}{}: {}", marker, i, state),
+                }
+            }
+            if corrupt {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(("exists", args)) => {
+            let (typ, name) = split_var(args.get_one::<String>("variable").unwrap(), default_typ)?;
+            let name = read_var(name)?;
+            let mut nv = parse_image(&data)?;
+            if !nv.active_part_mut().contains(&name, typ) {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(("grep", args)) => {
+            let pattern = read_var(args.get_one::<String>("pattern").unwrap())?;
+            let ignore_case = args.contains_id("ignore-case");
+            let mut nv = parse_image(&data)?;
+            let mut found = false;
+            for var in nv.active_part_mut().variables() {
+                if contains_bytes(&var.value(), &pattern, ignore_case)
+                    || args.contains_id("name") && contains_bytes(var.name(), &pattern, ignore_case)
+                {
+                    println!("{}", var);
+                    found = true;
+                }
+            }
+            if !found {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(("usage", args)) => {
+            let mut nv = parse_image(&data)?;
+            let part = nv.active_part_mut();
+            let top = *args.get_one::<usize>("top").unwrap();
+            for typ in [VarType::Common, VarType::System] {
+                println!(
+                    "{}: {} bytes, {} used, {} free",
+                    typ,
+                    part.capacity(typ),
+                    part.used_space(typ),
+                    part.free_space(typ)
+                );
+                // name and value, what the format adds around them isn't counted
+                let mut vars: Vec<_> = part
+                    .variables()
+                    .filter(|v| v.typ() == typ)
+                    .map(|v| (v.name().len() + v.value().len(), v.name()))
+                    .collect();
+                match args.get_one::<String>("sort").unwrap().as_str() {
+                    "name" => vars.sort_by_key(|&(_, name)| name),
+                    _ => vars.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1))),
+                }
+                let shown = if top == 0 { vars.len() } else { top };
+                for (size, name) in vars.iter().take(shown) {
+                    println!("  {:>8}  {}", size, escape_value(name));
+                }
+                if vars.len() > shown {
+                    println!("  ({} more)", vars.len() - shown);
+                }
+            }
+        }
+        Some(("info", args)) => {
+            let nv = parse_image(&data)?;
+            let device = match &file {
+                Dest::File(file) => Some(device_size(file)?),
+                Dest::Stdout => None,
+            };
+            print_info(&*nv, data.len(), device, args.contains_id("json"));
+        }
+        Some(("list-partitions", _args)) => {
+            let nv = parse_image(&data)?;
+            let active = nv.active_index();
+            for (i, part) in nv.partitions().enumerate() {
+                let marker = if i == active { "*" } else { " " };
+                println!("{}{}: {}", marker, i, part);
+                for typ in [VarType::Common, VarType::System] {
+                    println!(
+                        "    {}: {}/{} bytes used, {} variables",
+                        typ,
+                        part.used_space(typ),
+                        part.capacity(typ),
+                        part.variables().filter(|v| v.typ() == typ).count()
+                    );
+                }
             }
-            nv.apply(&mut MtdWriter::new(file))?;
         }
         _ => {}
     }
+    Ok(ExitCode::SUCCESS)
+}
+
+// a corrupt bank is skipped over unless --strict, with a warning since the
+// values read may be older than expected
+fn parse_image(data: &[u8]) -> Result<Box<dyn Nvram<'_> + '_>> {
+    if STRICT.load(Ordering::Relaxed) {
+        return Ok(nvram_parse_strict(data)?);
+    }
+    let nv = nvram_parse(data)?;
+    if nv.recovered() {
+        let corrupt: Vec<_> = nv
+            .bank_states()
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| **s == BankState::Invalid)
+            .map(|(i, _)| i.to_string())
+            .collect();
+        eprintln!(
+            "warning: bank {} is corrupt, using bank {}",
+            corrupt.join(", "),
+            nv.active_bank()
+        );
+    }
+    if let Some(part) = nv.bank(nv.active_bank()) {
+        if part.duplicates() > 0 {
+            verbose!(
+                1,
+                "warning: {} duplicate variables, using the last of each",
+                part.duplicates()
+            );
+        }
+    }
+    Ok(nv)
+}
+
+// which mtd device holds the nvram differs between machines, so look at
+// the start of each of them, going back to /dev/mtd0 if none look right
+fn find_device() -> Result<String> {
+    let dir = std::fs::read_dir("/dev").map_err(|e| Error::DeviceOpen("/dev".into(), e))?;
+    // mtdN only, mtdNro and mtdblockN are the same partitions again
+    let mut devices: Vec<u32> = dir
+        .flatten()
+        .filter_map(|e| e.file_name().to_str()?.strip_prefix("mtd")?.parse().ok())
+        .collect();
+    devices.sort_unstable();
+    let mut found = Vec::new();
+    for n in devices {
+        let path = format!("/dev/mtd{}", n);
+        let mut header = [0; 16];
+        let nvram = File::open(&path)
+            .and_then(|mut f| f.read_exact(&mut header))
+            .is_ok()
+            && is_nvram_header(&header);
+        verbose!(2, "{}: {}", path, if nvram { "nvram" } else { "not nvram" });
+        if nvram {
+            found.push(path);
+        }
+    }
+    match found.len() {
+        // whatever it is, the error opening or parsing it says what's wrong
+        0 => Ok("/dev/mtd0".into()),
+        1 => Ok(found.remove(0)),
+        _ => Err(Error::AmbiguousDevice(found)),
+    }
+}
+
+// a typo here can leave the machine unbootable, so make the user look at
+// what is about to happen first
+fn confirm(changes: &[String], force: bool) -> Result<bool> {
+    if force {
+        return Ok(true);
+    }
+    if !stdin().is_terminal() {
+        return Err(Error::ConfirmationRequired);
+    }
+    // on stderr, stdout may be where the image is going
+    for change in changes {
+        eprintln!("{}", change);
+    }
+    eprint!("confirm? [y/N]: ");
+    let mut input = String::new();
+    stdin()
+        .read_line(&mut input)
+        .map_err(Error::ValueReadError)?;
+    Ok(input.trim().to_lowercase() == "y")
+}
+
+fn editor() -> String {
+    std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".into())
+}
+
+// runs the editor on a temporary file holding text until what it's saved
+// as unescapes and passes check, offering to go back in when it doesn't.
+// None if the file was emptied
+fn edit_value(
+    editor: &str,
+    text: &str,
+    check: &dyn Fn(&[u8]) -> Result<()>,
+) -> Result<Option<Vec<u8>>> {
+    // a random name can't be set up ahead of time by someone else, and one
+    // left behind is skipped over like save_backup does
+    for n in 0u32.. {
+        let path = std::env::temp_dir().join(format!(
+            "asahi-nvram-edit-{}-{:016x}",
+            std::process::id(),
+            RandomState::new().hash_one(n)
+        ));
+        // not readable by anyone else, values can be secrets
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut f| writeln!(f, "{}", text));
+        match created {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(Error::EditError(e)),
+        }
+        let ret = edit_file(&path, editor, check);
+        let _ = std::fs::remove_file(&path);
+        return ret;
+    }
+    unreachable!()
+}
+
+fn edit_file(
+    path: &Path,
+    editor: &str,
+    check: &dyn Fn(&[u8]) -> Result<()>,
+) -> Result<Option<Vec<u8>>> {
+    loop {
+        // through the shell, EDITOR is allowed to have arguments
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor))
+            .arg("sh")
+            .arg(path)
+            .status()
+            .map_err(Error::EditError)?;
+        if !status.success() {
+            let why = format!("{} exited with {}", editor, status);
+            return Err(Error::EditError(std::io::Error::other(why)));
+        }
+        let edited = std::fs::read_to_string(path).map_err(Error::EditError)?;
+        // the escaped form has no line breaks of its own, these are the editor's
+        let edited = edited.trim_end_matches(['\r', '\n']);
+        if edited.is_empty() {
+            return Ok(None);
+        }
+        let err = match read_var(edited) {
+            Ok(value) => match check(&value) {
+                Ok(()) => return Ok(Some(value)),
+                Err(e) => e,
+            },
+            Err(e) => e,
+        };
+        if !stdin().is_terminal() {
+            return Err(err);
+        }
+        eprint!("error: {}, edit again? [Y/n]: ", err);
+        let mut input = String::new();
+        stdin()
+            .read_line(&mut input)
+            .map_err(Error::ValueReadError)?;
+        if input.trim().eq_ignore_ascii_case("n") {
+            return Err(err);
+        }
+    }
+}
+
+// returns the variables of the active partition of an export
+fn read_export(doc: &str) -> Result<Vec<OwnedVariable>> {
+    let doc = json::parse(doc).ok_or(Error::InvalidImport)?;
+    let active = doc
+        .get("active")
+        .and_then(json::Value::as_usize)
+        .ok_or(Error::InvalidImport)?;
+    let part = doc
+        .get("partitions")
+        .and_then(json::Value::as_array)
+        .and_then(|parts| {
+            parts
+                .iter()
+                .find(|p| p.get("index").and_then(json::Value::as_usize) == Some(active))
+        })
+        .ok_or(Error::InvalidImport)?;
+    let mut ret = Vec::new();
+    for var in part
+        .get("variables")
+        .and_then(json::Value::as_array)
+        .ok_or(Error::InvalidImport)?
+    {
+        let field = |name| {
+            var.get(name)
+                .and_then(json::Value::as_str)
+                .ok_or(Error::InvalidImport)
+        };
+        ret.push(OwnedVariable {
+            typ: part_by_name(field("partition")?)?,
+            name: read_var(field("name")?)?,
+            value: read_var(field("value")?)?,
+        });
+    }
+    Ok(ret)
+}
+
+// (type, name) -> value
+type VarMap = BTreeMap<(VarType, Vec<u8>), Vec<u8>>;
+
+fn read_image_vars(path: &str) -> Result<VarMap> {
+    let data = std::fs::read(path).map_err(Error::BackupReadError)?;
+    let mut nv = nvram_parse(&data)?;
+    Ok(nv
+        .active_part_mut()
+        .variables()
+        .map(|v| ((v.typ(), v.name().to_vec()), v.value().into_owned()))
+        .collect())
+}
+
+// exits with 1 if there are any differences, like diff(1)
+fn diff(old: &str, new: &str) -> Result<ExitCode> {
+    let old = read_image_vars(old)?;
+    let new = read_image_vars(new)?;
+    let mut differ = false;
+    let keys: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+    for key @ (typ, name) in keys {
+        let (old_val, new_val) = (old.get(key), new.get(key));
+        if old_val == new_val {
+            continue;
+        }
+        differ = true;
+        if let Some(v) = old_val {
+            println!("- {}:{}={}", typ, escape_value(name), escape_value(v));
+        }
+        if let Some(v) = new_val {
+            println!("+ {}:{}={}", typ, escape_value(name), escape_value(v));
+        }
+    }
+    Ok(if differ {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+// with the current contents given, only the blocks that differ from them
+// are erased and written
+fn commit<'a>(
+    nv: &mut dyn Nvram<'a>,
+    file: Dest,
+    dry_run: bool,
+    current: Option<&[u8]>,
+) -> Result<()> {
+    if VERBOSITY.load(Ordering::Relaxed) >= 2 {
+        verbose!(2, "writing {} bytes", nv.serialize()?.len());
+    }
+    if !dry_run {
+        let file = match file {
+            Dest::File(file) => file,
+            Dest::Stdout => return write_stdout(&nv.serialize()?),
+        };
+        // the check is against the end of what apply writes, which is past
+        // the bank it started from if it rolled over
+        match current {
+            Some(current) => {
+                let block_size = mtd::erase_size(&file).unwrap_or(4096);
+                let mut image = VecWriter::new(current.to_vec());
+                nv.apply(&mut image)?;
+                check_fits(&file, image.bytes().len())?;
+                let written = image
+                    .write_changes(current, block_size, &mut *writer(file)?)
+                    .map_err(apple_nvram::Error::ApplyError)?;
+                verbose!(
+                    1,
+                    "wrote {} bytes in changed blocks of {}",
+                    written,
+                    block_size
+                );
+            }
+            None => {
+                let mut end = VecWriter::default();
+                nv.boxed_clone().apply(&mut end)?;
+                check_fits(&file, end.bytes().len())?;
+                let written = nv.apply(&mut *writer(file)?)?;
+                verbose!(1, "wrote {} bytes", written);
+            }
+        }
+        return Ok(());
+    }
+    print_image(&nv.serialize()?)
+}
+
+// "active", "inactive" (the most recent other valid bank) or an index
+fn select_bank<'a>(nv: &dyn Nvram<'a>, bank: Option<&String>) -> Result<usize> {
+    let active = nv.active_bank();
+    match bank.map(String::as_str) {
+        None | Some("active") => Ok(active),
+        Some("inactive") => (0..nv.bank_states().len())
+            .filter(|&i| i != active)
+            .filter_map(|i| nv.bank(i).map(|p| (p.generation(), i)))
+            .max()
+            .map(|(_, i)| i)
+            .ok_or(Error::InvalidBank),
+        Some(index) => match index.parse() {
+            Ok(i) if i < nv.bank_states().len() => Ok(i),
+            _ => Err(Error::InvalidBank),
+        },
+    }
+}
+
+// where a changed image goes, stdout is for `--output -`
+enum Dest {
+    File(File),
+    Stdout,
+}
+
+fn write_stdout(image: &[u8]) -> Result<()> {
+    let mut out = stdout();
+    out.write_all(image).map_err(Error::OutputError)?;
+    out.flush().map_err(Error::OutputError)
+}
+
+// replaces the image as a whole, for restore and fix-crc
+fn write_whole(file: Dest, image: &[u8]) -> Result<()> {
+    let file = match file {
+        Dest::File(file) => file,
+        Dest::Stdout => return write_stdout(image),
+    };
+    check_fits(&file, image.len())?;
+    let mut w = writer(file)?;
+    w.erase_if_needed(0, image.len());
+    Ok(w.write_all(0, image)
+        .map_err(apple_nvram::Error::ApplyError)?)
+}
+
+// nvram-<UTC time>.bin, two saved within the same second get a -N suffix
+// instead of replacing each other
+fn save_backup(dir: &Path, data: &[u8]) -> std::io::Result<PathBuf> {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let stamp = utc_timestamp(secs);
+    for n in 0.. {
+        let path = match n {
+            0 => dir.join(format!("nvram-{}.bin", stamp)),
+            n => dir.join(format!("nvram-{}-{}.bin", stamp, n)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut f) => return f.write_all(data).and_then(|_| f.sync_all()).map(|_| path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+// YYYYMMDDTHHMMSSZ, days to a date as in Howard Hinnant's civil_from_days
+fn utc_timestamp(secs: u64) -> String {
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+// what an mtd device reports, or for anything else, the file length
+fn device_size(file: &File) -> Result<u64> {
+    match mtd::size(file) {
+        Some(size) => Ok(size),
+        None => Ok(file.metadata().map_err(Error::ApplyError)?.len()),
+    }
+}
+
+// an image file could grow to fit, but whatever follows the nvram on
+// flash would be overwritten
+fn check_fits(file: &File, size: usize) -> Result<()> {
+    let device = device_size(file)?;
+    if size as u64 > device {
+        return Err(Error::ImageTooBig(size, device));
+    }
     Ok(())
 }
 
+// only mtd devices need erasing before they can be written, and with
+// --verify every write is read back through a second handle
+fn writer(file: File) -> Result<Box<dyn NvramWriter>> {
+    let meta = file.metadata().map_err(Error::ApplyError)?;
+    let readback = match VERIFY.load(Ordering::Relaxed) {
+        true => Some(file.try_clone().map_err(Error::ApplyError)?),
+        false => None,
+    };
+    fn wrap<W: NvramWriter + 'static>(w: W, readback: Option<File>) -> Box<dyn NvramWriter> {
+        match readback {
+            Some(file) => Box::new(VerifyingWriter::new(w, file)),
+            None => Box::new(w),
+        }
+    }
+    if meta.file_type().is_char_device() {
+        Ok(wrap(MtdWriter::new(file), readback))
+    } else {
+        Ok(wrap(FileWriter::new(file), readback))
+    }
+}
+
+fn print_image(image: &[u8]) -> Result<()> {
+    let mut out = stdout();
+    if out.is_terminal() {
+        println!(
+            "{} bytes, crc32: {:08x}",
+            image.len(),
+            crc32fast::hash(image)
+        );
+    } else {
+        out.write_all(image).map_err(Error::OutputError)?;
+    }
+    Ok(())
+}
+
+// how a name on the command line picks variables
+enum Selector {
+    Name(Vec<u8>),
+    Glob(Vec<u8>),
+}
+
+impl Selector {
+    fn new(name: &str) -> Result<Selector> {
+        Ok(match is_glob(name) {
+            true => Selector::Glob(read_var(name)?),
+            false => Selector::Name(read_var(name)?),
+        })
+    }
+
+    fn matches(&self, name: &[u8]) -> bool {
+        match self {
+            Selector::Name(n) => n == name,
+            Selector::Glob(pattern) => glob_match(pattern, name),
+        }
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Selector::Name(name) | Selector::Glob(name) => write!(f, "{}", escape_value(name)),
+        }
+    }
+}
+
+fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+// shell style, `*` matches any run of bytes and `?` exactly one
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+// system variables that the machine can't boot without, a guard against
+// mistakes rather than any kind of security boundary
+const PROTECTED_VARIABLES: [&[u8]; 3] =
+    [b"boot-info-payload", b"boot-volume", b"upgrade-boot-volume"];
+
+fn check_protected(typ: VarType, name: &[u8], allow: bool) -> Result<()> {
+    if allow || typ != VarType::System || !PROTECTED_VARIABLES.contains(&name) {
+        return Ok(());
+    }
+    Err(Error::ProtectedVariable(format!(
+        "{}:{}",
+        typ,
+        escape_value(name)
+    )))
+}
+
+enum BatchOp {
+    Set(VarType, Vec<u8>, Vec<u8>),
+    Delete(VarType, Vec<u8>),
+}
+
+// blank lines and lines starting with # are skipped
+fn parse_batch(batch: &str, default: VarType) -> Result<Vec<BatchOp>> {
+    let mut ret = Vec::new();
+    for (i, line) in batch.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |_| Error::InvalidBatch(i + 1);
+        let op = match line.split_once(' ') {
+            Some(("set", var)) => {
+                let (key, value) = var.split_once('=').ok_or(Error::InvalidBatch(i + 1))?;
+                let (typ, name) = split_var(key, default)?;
+                BatchOp::Set(
+                    typ,
+                    read_var(name).map_err(invalid)?,
+                    read_var(value).map_err(invalid)?,
+                )
+            }
+            Some(("del", var)) => {
+                let (typ, name) = split_var(var, default)?;
+                BatchOp::Delete(typ, read_var(name).map_err(invalid)?)
+            }
+            _ => return Err(Error::InvalidBatch(i + 1)),
+        };
+        ret.push(op);
+    }
+    Ok(ret)
+}
+
+// boot-args flags are whitespace separated, either bare or name=value
+fn edit_boot_args<'a>(
+    current: &str,
+    add: impl Iterator<Item = &'a String>,
+    remove: impl Iterator<Item = &'a String>,
+) -> String {
+    fn flag_name(flag: &str) -> &str {
+        flag.split_once('=').map_or(flag, |(name, _)| name)
+    }
+    let mut flags: Vec<&str> = current.split_whitespace().collect();
+    for flag in remove {
+        flags.retain(|f| f != flag && flag_name(f) != flag);
+    }
+    for flag in add {
+        flags.retain(|f| flag_name(f) != flag_name(flag));
+        flags.push(flag);
+    }
+    flags.join(" ")
+}
+
+// catches the obvious ways a hand-typed value goes wrong, a bad boot-args
+// can keep the machine from booting
+fn validate_boot_args(value: &[u8]) -> std::result::Result<(), &'static str> {
+    if value.contains(&0) {
+        return Err("contains a NUL byte");
+    }
+    let value = std::str::from_utf8(value).map_err(|_| "isn't valid UTF-8")?;
+    if value.chars().filter(|&c| c == '"').count() % 2 != 0 {
+        return Err("has an unbalanced quote");
+    }
+    Ok(())
+}
+
+// names the variable that didn't fit, the library only knows by how much
+fn too_big(e: apple_nvram::Error, typ: VarType, name: &[u8]) -> Error {
+    match e {
+        apple_nvram::Error::VariableTooBig(over) => {
+            Error::VariableTooBig(format!("{}:{}", typ, escape_value(name)), over)
+        }
+        e => e.into(),
+    }
+}
+
+// "part:name", or just "name" for the default partition
+fn split_var(var: &str, default: VarType) -> Result<(VarType, &str)> {
+    match var.split_once(':') {
+        Some((part, name)) => Ok((part_by_name(part)?, name)),
+        None => Ok((default, var)),
+    }
+}
+
+// like split_var, with write --partition both a name without a prefix goes
+// to more than one partition
+fn split_var_types<'a>(var: &'a str, default: &[VarType]) -> Result<(Vec<VarType>, &'a str)> {
+    match var.split_once(':') {
+        Some((part, name)) => Ok((vec![part_by_name(part)?], name)),
+        None => Ok((default.to_vec(), var)),
+    }
+}
+
+const PARTITION_NAMES: [&str; 2] = ["common", "system"];
+
 fn part_by_name(name: &str) -> Result<VarType> {
-    match name {
+    match name.to_lowercase().as_str() {
         "common" => Ok(VarType::Common),
         "system" => Ok(VarType::System),
-        _ => Err(Error::UnknownPartition),
+        _ => Err(Error::UnknownPartition(name.to_owned())),
     }
 }
 
+// a name=value argument whose value is `-`, a value merely ending in one
+// is taken as it is
+fn reads_stdin(var: &str) -> bool {
+    var.split_once('=').is_some_and(|(_, value)| value == "-")
+}
+
+// `@path` loads the value verbatim from a file, `@@` escapes a literal `@`
+// and `-` reads it from stdin
+fn read_value(val: &str) -> Result<Vec<u8>> {
+    if val == "-" {
+        let mut ret = Vec::new();
+        stdin()
+            .read_to_end(&mut ret)
+            .map_err(Error::ValueReadError)?;
+        return Ok(ret);
+    }
+    match val.strip_prefix('@') {
+        Some(path) if !path.starts_with('@') => std::fs::read(path).map_err(Error::ValueReadError),
+        Some(val) => read_var(val),
+        None => read_var(val),
+    }
+}
+
+// like read_base64, whitespace in what `-` and `@path` give is skipped
+fn read_hex(val: &str) -> Result<Vec<u8>> {
+    if val == "-" || val.starts_with('@') {
+        let raw = read_value(val)?;
+        let digits: String = String::from_utf8_lossy(&raw)
+            .split_ascii_whitespace()
+            .collect();
+        parse_hex(&digits)
+    } else {
+        parse_hex(val)
+    }
+}
+
+fn hex_encode(val: &[u8]) -> String {
+    val.iter().map(|c| format!("{c:02x}")).collect()
+}
+
+// a BOM decides the byte order and is dropped, without one it's little
+// endian like everything else apple stores
+fn utf16_decode(val: &[u8]) -> Result<String> {
+    if !val.len().is_multiple_of(2) {
+        return Err(Error::InvalidUtf16);
+    }
+    let (val, from_bytes): (_, fn([u8; 2]) -> u16) = match val {
+        [0xFE, 0xFF, rest @ ..] => (rest, u16::from_be_bytes),
+        [0xFF, 0xFE, rest @ ..] => (rest, u16::from_le_bytes),
+        _ => (val, u16::from_le_bytes),
+    };
+    char::decode_utf16(val.chunks(2).map(|c| from_bytes([c[0], c[1]])))
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|_| Error::InvalidUtf16)
+}
+
+// big-endian, two digits per byte
+fn parse_hex(val: &str) -> Result<Vec<u8>> {
+    if !val.len().is_multiple_of(2) || !val.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::InvalidHex);
+    }
+    (0..val.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&val[i..i + 2], 16).map_err(|_| Error::InvalidHex))
+        .collect()
+}
+
+// what escape_value and Display print, read back
 fn read_var(val: &str) -> Result<Vec<u8>> {
-    let val = val.as_bytes();
-    let mut ret = Vec::new();
-    let mut i = 0;
-    while i < val.len() {
-        if val[i] == b'%' {
-            ret.push(
-                u8::from_str_radix(
-                    unsafe { std::str::from_utf8_unchecked(&val[i + 1..i + 3]) },
-                    16,
-                )
-                .map_err(|_| Error::InvalidHex)?,
+    Ok(unescape_value(val)?)
+}
+
+fn base64_encode(val: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut ret = String::with_capacity(val.len().div_ceil(3) * 4);
+    for chunk in val.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    ret
+}
+
+// `-` and `@path` are read first, whatever they contain is decoded
+fn read_base64(val: &str) -> Result<Vec<u8>> {
+    if val == "-" || val.starts_with('@') {
+        base64_decode(&read_value(val)?)
+    } else {
+        base64_decode(val.as_bytes())
+    }
+    .ok_or(Error::InvalidBase64)
+}
+
+// whitespace is skipped so wrapped output decodes too
+fn base64_decode(val: &[u8]) -> Option<Vec<u8>> {
+    let mut ret = Vec::with_capacity(val.len() / 4 * 3);
+    let (mut acc, mut bits, mut count, mut padding) = (0u32, 0, 0, 0);
+    for &c in val.iter().filter(|c| !c.is_ascii_whitespace()) {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                padding += 1;
+                continue;
+            }
+            _ => return None,
+        };
+        if padding > 0 {
+            return None;
+        }
+        acc = (acc << 6 | v as u32) & 0xFFF;
+        bits += 6;
+        count += 1;
+        if bits >= 8 {
+            bits -= 8;
+            ret.push((acc >> bits) as u8);
+        }
+    }
+    if count % 4 == 1 || (padding > 0 && (count + padding) % 4 != 0) {
+        return None;
+    }
+    Some(ret)
+}
+
+fn json_variable(var: &dyn Variable) -> String {
+    format!(
+        "\"partition\": {}, \"name\": {}, \"value\": {}",
+        json::string(&var.typ().to_string()),
+        json::string(&escape_value(var.name())),
+        json::string(&escape_value(&var.value())),
+    )
+}
+
+fn print_json(vars: &[&dyn Variable]) {
+    println!("[");
+    for (i, var) in vars.iter().enumerate() {
+        println!(
+            "  {{{}, \"base64\": {}}}{}",
+            json_variable(*var),
+            json::string(&base64_encode(&var.value())),
+            if i + 1 < vars.len() { "," } else { "" }
+        );
+    }
+    println!("]");
+}
+
+// the space figures are for the active bank, the one that gets written to
+// device is None for an image read from stdin
+fn print_info<'a>(nv: &dyn Nvram<'a>, size: usize, device: Option<u64>, json: bool) {
+    let states = nv.bank_states();
+    let active = nv.active_bank();
+    let part = nv.bank(active);
+    let generation = part.map_or(0, |p| p.generation());
+    let types = [VarType::Common, VarType::System];
+    let count = |part: &dyn Partition<'a>, typ| part.variables().filter(|v| v.typ() == typ).count();
+    if !json {
+        println!("version: {}", nv.version());
+        println!("size: {} bytes", size);
+        if let Some(device) = device {
+            println!("device size: {} bytes", device);
+        }
+        println!(
+            "active bank: {}, generation {} ({})",
+            active,
+            generation,
+            nv.active_reason()
+        );
+        for (i, state) in states.iter().enumerate() {
+            match nv.bank(i) {
+                Some(p) => println!("bank {}: {}, generation {}", i, state, p.generation()),
+                None => println!("bank {}: {}", i, state),
+            }
+        }
+        for typ in types {
+            let Some(part) = part else { break };
+            println!(
+                "{}: {}/{} bytes used, {} free, {} variables",
+                typ,
+                part.used_space(typ),
+                part.capacity(typ),
+                part.free_space(typ),
+                count(part, typ)
+            );
+        }
+        return;
+    }
+    println!("{{");
+    println!(
+        "  \"version\": {},",
+        json::string(&nv.version().to_string())
+    );
+    println!("  \"size\": {},", size);
+    println!(
+        "  \"device_size\": {},",
+        device.map_or("null".into(), |d| d.to_string())
+    );
+    println!("  \"active_bank\": {},", active);
+    println!(
+        "  \"active_reason\": {},",
+        json::string(&nv.active_reason().to_string())
+    );
+    println!("  \"generation\": {},", generation);
+    println!("  \"banks\": [");
+    for (i, state) in states.iter().enumerate() {
+        let generation = nv
+            .bank(i)
+            .map_or("null".into(), |p| p.generation().to_string());
+        println!(
+            "    {{\"index\": {}, \"state\": {}, \"generation\": {}}}{}",
+            i,
+            json::string(&state.to_string()),
+            generation,
+            if i + 1 < states.len() { "," } else { "" }
+        );
+    }
+    println!("  ],");
+    println!("  \"partitions\": [");
+    if let Some(part) = part {
+        for (i, typ) in types.into_iter().enumerate() {
+            println!(
+                "    {{\"name\": {}, \"used\": {}, \"capacity\": {}, \"free\": {}, \"variables\": {}}}{}",
+                json::string(&typ.to_string()),
+                part.used_space(typ),
+                part.capacity(typ),
+                part.free_space(typ),
+                count(part, typ),
+                if i + 1 < types.len() { "," } else { "" }
+            );
+        }
+    }
+    println!("  ]");
+    println!("}}");
+}
+
+// variables are sorted so that two exports can be meaningfully diffed
+fn print_export<'a>(nv: &dyn Nvram<'a>) {
+    println!("{{");
+    println!(
+        "  \"version\": {},",
+        json::string(&nv.version().to_string())
+    );
+    println!("  \"active\": {},", nv.active_index());
+    println!("  \"partitions\": [");
+    let count = nv.partitions().count();
+    for (i, part) in nv.partitions().enumerate() {
+        let mut vars: Vec<_> = part.variables().collect();
+        vars.sort_by_key(|v| (v.typ(), v.name()));
+        println!("    {{");
+        println!("      \"index\": {},", i);
+        println!("      \"variables\": [");
+        for (j, var) in vars.iter().enumerate() {
+            println!(
+                "        {{{}}}{}",
+                json_variable(*var),
+                if j + 1 < vars.len() { "," } else { "" }
             );
-            i += 2;
+        }
+        println!("      ]");
+        println!("    }}{}", if i + 1 < count { "," } else { "" });
+    }
+    println!("  ]");
+    println!("}}");
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// matches the output of `nvram -x` on macos, where system variables are
+// keyed by their guid and common ones by their bare name
+fn print_plist(vars: &[&dyn Variable]) {
+    println!(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    println!(
+        r#"<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">"#
+    );
+    println!(r#"<plist version="1.0">"#);
+    println!("<dict>");
+    for var in vars {
+        let name = String::from_utf8_lossy(var.name());
+        let key = match var.typ() {
+            VarType::Common => name.into_owned(),
+            VarType::System => format!("{}:{}", SYSTEM_GUID, name),
+        };
+        println!("\t<key>{}</key>", xml_escape(&key));
+        let value = var.value();
+        match std::str::from_utf8(&value) {
+            Ok(s) if !s.chars().any(|c| c.is_control() && !"\t\n\r".contains(c)) => {
+                println!("\t<string>{}</string>", xml_escape(s))
+            }
+            _ => println!("\t<data>{}</data>", base64_encode(&value)),
+        }
+    }
+    println!("</dict>");
+    println!("</plist>");
+}
+
+// `name='value'` for a shell to eval, with anything in the name that isn't
+// allowed in a shell variable turned into `_`; values that aren't text get
+// a _B64 suffix on the name and are base64 encoded instead
+fn env_line(name: &[u8], value: &[u8]) -> String {
+    let mut name: String = name
+        .iter()
+        .map(|&c| {
+            if c.is_ascii_alphanumeric() {
+                c as char
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        name.insert(0, '_');
+    }
+    // NUL terminators are dropped, a NUL anywhere else makes it binary
+    let text = &value[..value.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1)];
+    match std::str::from_utf8(text) {
+        Ok(text) if !text.contains('\0') => {
+            format!("{}='{}'", name, text.replace('\'', r"'\''"))
+        }
+        _ => format!("{}_B64='{}'", name, base64_encode(value)),
+    }
+}
+
+// plain name=value lines the way open firmware lists them, only what would
+// break the line format is %XX-escaped and the rest is left as is
+fn chrp_escape(val: &[u8], out: &mut Vec<u8>) {
+    for &c in val {
+        if c.is_ascii_control() || c == b'%' {
+            out.extend_from_slice(format!("%{c:02x}").as_bytes());
         } else {
-            ret.push(val[i])
+            out.push(c);
         }
-        i += 1;
     }
-    Ok(ret)
+}
+
+fn print_chrp(vars: &[&dyn Variable]) -> Result<()> {
+    let mut buf = Vec::new();
+    for var in vars {
+        chrp_escape(var.name(), &mut buf);
+        buf.push(b'=');
+        chrp_escape(&var.value(), &mut buf);
+        buf.push(b'\n');
+    }
+    let mut out = stdout();
+    out.write_all(&buf).map_err(Error::OutputError)?;
+    out.flush().map_err(Error::OutputError)
+}
+
+// an empty needle is found everywhere
+fn contains_bytes(haystack: &[u8], needle: &[u8], ignore_case: bool) -> bool {
+    needle.is_empty()
+        || haystack.windows(needle.len()).any(|w| match ignore_case {
+            true => w.eq_ignore_ascii_case(needle),
+            false => w == needle,
+        })
+}
+
+// concatenating several raw values would make them impossible to tell apart
+fn print_raw(vars: &[&dyn Variable]) -> Result<()> {
+    let [var] = vars else {
+        return Err(Error::AmbiguousRawOutput);
+    };
+    let mut out = stdout();
+    out.write_all(&var.value()).map_err(Error::OutputError)?;
+    out.flush().map_err(Error::OutputError)
+}
+
+type Decoder = fn(&dyn Variable) -> Option<String>;
+
+// what read --decode knows about, a decoder returning None leaves the value
+// as it always is
+const DECODERS: &[(VarType, &[u8], Decoder)] = &[
+    (VarType::Common, b"auto-boot", |v| {
+        v.as_bool().map(|b| b.to_string())
+    }),
+    (VarType::Common, b"boot-args", |v| {
+        Some(
+            v.as_str()?
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }),
+    (VarType::Common, b"prev-lang:kbd", |v| {
+        let text = v.as_str()?;
+        let (lang, kbd) = text.split_once(':')?;
+        Some(format!("language {}, keyboard layout {}", lang, kbd))
+    }),
+    (VarType::Common, b"SystemAudioVolume", |v| {
+        match v.value().as_ref() {
+            [volume] => Some(volume.to_string()),
+            _ => None,
+        }
+    }),
+];
+
+fn decode_value(var: &dyn Variable) -> Option<String> {
+    let (_, _, decode) = DECODERS
+        .iter()
+        .find(|(typ, name, _)| *typ == var.typ() && *name == var.name())?;
+    decode(var)
+}
+
+// what read --decode prints, marked so it can't be taken for the value
+fn decoded_line(var: &dyn Variable) -> Option<String> {
+    let decoded = decode_value(var)?;
+    let line = var.to_string();
+    // the name has its '=' escaped, the first one ends it
+    let (name, _) = line.split_once('=')?;
+    Some(format!("{} (decoded)={}", name, decoded))
+}
+
+// over what Display gives for a variable: the partition dim, the name bold
+// and every %XX escape in yellow, so bytes that aren't text stand out
+fn colorize(line: &str) -> String {
+    const RESET: &str = "\x1b[0m";
+    fn paint(out: &mut String, s: &str, sgr: &str) {
+        out.push_str(sgr);
+        let mut rest = s;
+        // '%' is escaped itself, so every one starts an escape
+        while let Some(i) = rest.find('%') {
+            let end = (i + 3).min(rest.len());
+            out.push_str(&rest[..i]);
+            out.push_str("\x1b[33m");
+            out.push_str(&rest[i..end]);
+            out.push_str(RESET);
+            out.push_str(sgr);
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        out.push_str(RESET);
+    }
+    // names have their '=' escaped, the first one ends them
+    let Some((typ, var)) = line.split_once(':') else {
+        return line.to_owned();
+    };
+    let (name, value) = var.split_once('=').unwrap_or((var, ""));
+    let mut out = String::new();
+    paint(&mut out, typ, "\x1b[2m");
+    out.push(':');
+    paint(&mut out, name, "\x1b[1m");
+    out.push('=');
+    paint(&mut out, value, "");
+    out
+}
+
+fn hexdump(val: &[u8]) -> String {
+    let mut ret = String::new();
+    for (i, line) in val.chunks(16).enumerate() {
+        ret.push_str(&format!("{:08x} ", i * 16));
+        for j in 0..16 {
+            if j % 8 == 0 {
+                ret.push(' ');
+            }
+            match line.get(j) {
+                Some(c) => ret.push_str(&format!("{c:02x} ")),
+                None => ret.push_str("   "),
+            }
+        }
+        ret.push_str(" |");
+        for &c in line {
+            ret.push(if c.is_ascii_graphic() || c == b' ' {
+                c as char
+            } else {
+                '.'
+            });
+        }
+        ret.push_str("|\n");
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_round_trip() {
+        let vals: &[&[u8]] = &[
+            b"",
+            b"boot-args",
+            b"100%",
+            b"a\0b\0",
+            b"\xff\x00\n=:\"",
+            b"%zz",
+        ];
+        for &val in vals {
+            assert_eq!(read_var(&escape_value(val)).unwrap(), val);
+        }
+    }
+
+    #[test]
+    fn test_split_var_types() {
+        let both = [VarType::Common, VarType::System];
+        let (types, name) = split_var_types("foo", &both).unwrap();
+        assert_eq!((&types[..], name), (&both[..], "foo"));
+        let (types, name) = split_var_types("system:foo", &both).unwrap();
+        assert_eq!((&types[..], name), (&[VarType::System][..], "foo"));
+        assert!(matches!(
+            split_var_types("nope:foo", &both),
+            Err(Error::UnknownPartition(_))
+        ));
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(0), "19700101T000000Z");
+        assert_eq!(utc_timestamp(951782400), "20000229T000000Z");
+        assert_eq!(utc_timestamp(1700000000), "20231114T221320Z");
+    }
+
+    #[test]
+    fn test_edit_value() {
+        let dir = std::env::temp_dir().join(format!("asahi-nvram-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // stand-ins for an editor, passed the file to change
+        let script = |name: &str, body: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, body).unwrap();
+            format!("sh {}", path.display())
+        };
+        let replace = script("replace.sh", "echo 'new%21' > \"$1\"\n");
+        let empty = script("empty.sh", ": > \"$1\"\n");
+        let ok = |_: &[u8]| Ok(());
+        let edit = |editor: &str| edit_value(editor, "old%00", &ok).unwrap();
+        assert_eq!(edit("true"), Some(b"old\0".to_vec()));
+        assert_eq!(edit(&replace), Some(b"new!".to_vec()));
+        assert_eq!(edit(&empty), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            edit_value("false", "", &ok),
+            Err(Error::EditError(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_value() {
+        let image = apple_nvram::builder::NvramBuilder::new(apple_nvram::Version::V3)
+            .variable(VarType::Common, b"boot-args", b"-v  debug=0x14e")
+            .variable(VarType::Common, b"SystemAudioVolume", b"\x30")
+            .variable(VarType::Common, b"auto-boot", b"false")
+            .variable(VarType::Common, b"prev-lang:kbd", b"en-US:0")
+            .variable(VarType::System, b"boot-args", b"-v")
+            .variable(VarType::Common, b"other", b"1")
+            .build()
+            .unwrap();
+        let nv = nvram_parse(&image).unwrap();
+        let decoded: Vec<_> = nv
+            .bank(nv.active_bank())
+            .unwrap()
+            .variables()
+            .map(decode_value)
+            .collect();
+        assert_eq!(
+            decoded,
+            [
+                Some("-v, debug=0x14e".into()),
+                Some("48".into()),
+                Some("false".into()),
+                Some("language en-US, keyboard layout 0".into()),
+                None,
+                None,
+            ]
+        );
+        let part = nv.bank(nv.active_bank()).unwrap();
+        let var = part
+            .get_variable(b"prev-lang:kbd", VarType::Common)
+            .unwrap();
+        let line = decoded_line(var).unwrap();
+        assert_eq!(
+            line,
+            "common:prev-lang:kbd (decoded)=language en-US, keyboard layout 0"
+        );
+        assert!(colorize(&line).contains("\x1b[1mprev-lang:kbd (decoded)\x1b[0m="));
+    }
+
+    #[test]
+    fn test_colorize() {
+        assert_eq!(
+            colorize("common:a%3db=x%00"),
+            "\x1b[2mcommon\x1b[0m:\x1b[1ma\x1b[33m%3d\x1b[0m\x1b[1mb\x1b[0m=\
+             x\x1b[33m%00\x1b[0m\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_contains_bytes() {
+        assert!(contains_bytes(b"serial C02XYZ\0", b"c02x", true));
+        assert!(!contains_bytes(b"serial C02XYZ\0", b"c02x", false));
+        assert!(contains_bytes(b"a\0b", b"\0", false));
+        assert!(!contains_bytes(b"ab", b"abc", true));
+        assert!(contains_bytes(b"", b"", false));
+    }
+
+    #[test]
+    fn test_reads_stdin() {
+        assert!(reads_stdin("a=-"));
+        assert!(!reads_stdin("a=b=-"));
+        assert!(!reads_stdin("a=x-"));
+        assert!(!reads_stdin("a-"));
+    }
+
+    #[test]
+    fn test_read_value_file() {
+        assert_eq!(read_value("@@foo%00").unwrap(), b"@foo\0");
+        assert!(matches!(
+            read_value("@/nonexistent/asahi-nvram-test"),
+            Err(Error::ValueReadError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("deadBEEF").unwrap(), [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(parse_hex("").unwrap(), b"");
+        assert!(matches!(parse_hex("abc"), Err(Error::InvalidHex)));
+        assert!(matches!(parse_hex("zz"), Err(Error::InvalidHex)));
+        assert!(matches!(parse_hex("+1"), Err(Error::InvalidHex)));
+        assert_eq!(hex_encode(&parse_hex("00ff7a").unwrap()), "00ff7a");
+    }
+
+    #[test]
+    fn test_utf16_decode() {
+        assert_eq!(utf16_decode(b"h\0i\0").unwrap(), "hi");
+        assert_eq!(utf16_decode(b"\xff\xfeh\0").unwrap(), "h");
+        assert_eq!(utf16_decode(b"\xfe\xff\0h").unwrap(), "h");
+        assert_eq!(utf16_decode(b"=\xd8\0\xde").unwrap(), "\u{1f600}");
+        assert!(matches!(utf16_decode(b"h\0i"), Err(Error::InvalidUtf16)));
+        // unpaired surrogate
+        assert!(matches!(
+            utf16_decode(b"=\xd8h\0"),
+            Err(Error::InvalidUtf16)
+        ));
+    }
+
+    #[test]
+    fn test_read_var_truncated_escape() {
+        assert!(matches!(read_var("abc%"), Err(Error::InvalidHex)));
+        assert!(matches!(read_var("abc%f"), Err(Error::InvalidHex)));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"boot*", b"boot-args"));
+        assert!(glob_match(b"boot*", b"boot"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a?c*z", b"abcxyz"));
+        assert!(!glob_match(b"a?c", b"ac"));
+        assert!(!glob_match(b"boot*", b"auto-boot"));
+        assert!(!glob_match(b"boot", b"boot-args"));
+    }
+
+    #[test]
+    fn test_parse_batch() {
+        let ops = parse_batch("# x\n\nset a=b%00\n  del system:c\n", VarType::Common).unwrap();
+        assert!(matches!(&ops[..], [
+            BatchOp::Set(VarType::Common, name, value),
+            BatchOp::Delete(VarType::System, del),
+        ] if name == b"a" && value == b"b\0" && del == b"c"));
+        assert!(matches!(
+            parse_batch("set a=b\nset c\n", VarType::Common),
+            Err(Error::InvalidBatch(2))
+        ));
+        assert!(matches!(
+            parse_batch("write a=b", VarType::Common),
+            Err(Error::InvalidBatch(1))
+        ));
+    }
+
+    #[test]
+    fn test_edit_boot_args() {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let add = strings(&["debug=0x14e", "quiet"]);
+        let remove = strings(&["serial"]);
+        assert_eq!(
+            edit_boot_args("  debug=1 serial=0  keep", add.iter(), remove.iter()),
+            "keep debug=0x14e quiet"
+        );
+        assert_eq!(edit_boot_args("", add[1..].iter(), [].iter()), "quiet");
+    }
+
+    #[test]
+    fn test_validate_boot_args() {
+        assert!(validate_boot_args(b"debug=0x14e serial=3 -v").is_ok());
+        assert!(validate_boot_args(b"a=\"b c\"").is_ok());
+        assert!(validate_boot_args(b"a=\"b c").is_err());
+        assert!(validate_boot_args(b"debug\0").is_err());
+        assert!(validate_boot_args(b"\xff").is_err());
+    }
+
+    #[test]
+    fn test_check_protected() {
+        assert!(check_protected(VarType::System, b"boot-volume", false).is_err());
+        assert!(check_protected(VarType::System, b"boot-volume", true).is_ok());
+        assert!(check_protected(VarType::Common, b"boot-volume", false).is_ok());
+        assert!(check_protected(VarType::System, b"boot-args", false).is_ok());
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foob\0"), "Zm9vYgA=");
+    }
+
+    #[test]
+    fn test_base64_decode() {
+        for val in [&b""[..], b"f", b"fo", b"foo", b"foob\0", &[0xFF; 40]] {
+            assert_eq!(base64_decode(base64_encode(val).as_bytes()).unwrap(), val);
+        }
+        assert_eq!(base64_decode(b"Zm9v\nYg").unwrap(), b"foob");
+        assert!(base64_decode(b"Zm9vY").is_none());
+        assert!(base64_decode(b"Zg=a").is_none());
+        assert!(base64_decode(b"Zm9v%").is_none());
+    }
+
+    #[test]
+    fn test_chrp_escape() {
+        let mut out = Vec::new();
+        chrp_escape(b"a b=\"100%\"\n\t\xe9", &mut out);
+        assert_eq!(out, b"a b=\"100%25\"%0a%09\xe9");
+    }
+
+    #[test]
+    fn test_env_line() {
+        assert_eq!(env_line(b"boot-args", b"-v\0"), "boot_args='-v'");
+        assert_eq!(env_line(b"9x", b"it's"), r"_9x='it'\''s'");
+        assert_eq!(env_line(b"a", b"\xff"), "a_B64='/w=='");
+        assert_eq!(env_line(b"a", b"x\0y"), "a_B64='eAB5'");
+    }
+
+    #[test]
+    fn test_hexdump() {
+        assert_eq!(hexdump(b""), "");
+        assert_eq!(
+            hexdump(b"boot-args=debug\0x"),
+            "00000000  62 6f 6f 74 2d 61 72 67  73 3d 64 65 62 75 67 00  |boot-args=debug.|\n\
+             00000010  78                                                |x|\n"
+        );
+    }
+
+    #[test]
+    fn test_commit_past_full_bank() {
+        let path =
+            std::env::temp_dir().join(format!("asahi-nvram-full-{}.bin", std::process::id()));
+        let image = apple_nvram::builder::NvramBuilder::new(apple_nvram::Version::V3)
+            .build()
+            .unwrap();
+        std::fs::write(&path, &image).unwrap();
+        // each write leaves the old copy behind, a bank holds about 64 of them
+        for i in 0..80u8 {
+            let data = std::fs::read(&path).unwrap();
+            let mut nv = nvram_parse(&data).unwrap();
+            nv.active_part_mut()
+                .insert_variable(b"test-variable", Cow::Owned(vec![i; 1000]), VarType::Common)
+                .unwrap();
+            let file = OpenOptions::new().write(true).open(&path).unwrap();
+            // both the incremental and the direct write
+            let current = (i % 2 == 0).then_some(&data[..]);
+            commit(&mut *nv, Dest::File(file), false, current).unwrap();
+        }
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), image.len());
+        let nv = nvram_parse(&data).unwrap();
+        assert_eq!(nv.active_bank(), 1);
+        let var = nv
+            .bank(1)
+            .unwrap()
+            .get_variable(b"test-variable", VarType::Common)
+            .unwrap();
+        assert_eq!(var.value(), vec![79; 1000]);
+    }
 }
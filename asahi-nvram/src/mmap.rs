@@ -0,0 +1,54 @@
+// SPDX-License-Identifier: MIT
+// Read-only mappings of image files, so that reading a single variable
+// doesn't have to copy the whole image first.
+use std::{ffi::c_void, fs::File, num::NonZeroUsize, os::unix::io::AsRawFd};
+
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+
+pub struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mapping {
+    // only regular files are mapped, mtd devices, pipes and empty files
+    // give None and have to be read in instead
+    pub fn new(file: &File) -> Option<Mapping> {
+        let meta = file.metadata().ok()?;
+        if !meta.is_file() {
+            return None;
+        }
+        let len = NonZeroUsize::new(meta.len().try_into().ok()?)?;
+        // SAFETY: the mapping is private and read-only, and nothing in this
+        // process writes to the file while it's mapped
+        let ptr = unsafe {
+            mmap(
+                None,
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        }
+        .ok()?;
+        Some(Mapping {
+            ptr,
+            len: len.get(),
+        })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        // SAFETY: ptr points to len readable bytes until drop
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: unmapping what new() mapped, no slices outlive self
+        unsafe {
+            let _ = munmap(self.ptr, self.len);
+        }
+    }
+}
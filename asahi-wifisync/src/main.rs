@@ -13,6 +13,7 @@ use apple_nvram::{nvram_parse, VarType, Variable};
 use ini::Ini;
 
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 enum Error {
     Parse,
     SectionTooBig,
@@ -26,8 +27,14 @@ impl From<apple_nvram::Error> for Error {
     fn from(e: apple_nvram::Error) -> Self {
         match e {
             apple_nvram::Error::ParseError => Error::Parse,
-            apple_nvram::Error::SectionTooBig => Error::SectionTooBig,
+            apple_nvram::Error::SectionTooBig | apple_nvram::Error::VariableTooBig(_) => {
+                Error::SectionTooBig
+            }
             apple_nvram::Error::ApplyError(e) => Error::ApplyError(e),
+            apple_nvram::Error::VariableNotFound => Error::VariableNotFound,
+            apple_nvram::Error::InvalidEscape => Error::Parse,
+            apple_nvram::Error::Anomaly(_) => Error::Parse,
+            apple_nvram::Error::InvalidLayout(_) => Error::Parse,
         }
     }
 }
@@ -68,9 +75,7 @@ fn real_main() -> Result<()> {
     file.read_to_end(&mut data).unwrap();
     let mut nv = nvram_parse(&data)?;
     let active = nv.active_part_mut();
-    let wlan_devs = active
-        .get_variable(wlan_var.as_bytes(), VarType::System)
-        .ok_or(Error::VariableNotFound)?;
+    let wlan_devs = active.try_get_variable(wlan_var.as_bytes(), VarType::System)?;
 
     match matches.subcommand() {
         Some(("list", _args)) => {